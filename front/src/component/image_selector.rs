@@ -1,10 +1,29 @@
 use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
 use gloo::file::File;
-use web_sys::HtmlInputElement;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{HtmlInputElement, ImageBitmap};
 use yew::prelude::*;
 
+const SUPPORTED_MIME_TYPES: [&str; 5] = [
+    "image/png",
+    "image/jpeg",
+    "image/webp",
+    "image/heic",
+    "image/heif",
+];
+
+/// Extensions of HEIF files, which some browsers give no MIME type.
+const HEIF_EXTENSIONS: [&str; 2] = [".heic", ".heif"];
+
+/// Sent for files the browser gave no MIME type; the server tells the
+/// format from the content.
+const UNKNOWN_MIME_TYPE: &str = "application/octet-stream";
+
 #[derive(Properties, PartialEq)]
 pub struct Props {
     pub on_change: Callback<Image>,
@@ -48,16 +67,22 @@ impl Component for ImageSelector {
                 for file in images.into_iter() {
                     let file_name = file.name();
                     let file_byte_size = file.size();
-                    let file_type = file.raw_mime_type();
+                    let file_type = match file.raw_mime_type() {
+                        t if t.is_empty() => UNKNOWN_MIME_TYPE.to_string(),
+                        t => t,
+                    };
 
                     ctx.link().send_future(async move {
                         let bytes = gloo::file::futures::read_as_bytes(&file).await;
+                        let dimensions = image_dimensions(&file).await;
 
                         match bytes {
                             Ok(bytes) => Msg::FileReady(Image {
                                 name: file_name,
                                 mime_type: file_type,
                                 size: file_byte_size,
+                                dimensions,
+                                hash: content_hash(&bytes),
                                 bytes: Rc::new(RefCell::new(bytes)),
                             }),
                             Err(_) => Msg::FileLoadError,
@@ -81,12 +106,20 @@ impl Component for ImageSelector {
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
+        let accept = SUPPORTED_MIME_TYPES
+            .iter()
+            .chain(&HEIF_EXTENSIONS)
+            .copied()
+            .collect::<Vec<_>>()
+            .join(",");
+
         html! {
             <div class="container image-selector">
                 <input
                     type="file"
                     multiple=true
                     name="images[]"
+                    accept={accept}
                     value={self.files_value.clone()}
                     onchange={ctx.link().callback(Self::on_change)}
                 />
@@ -107,12 +140,20 @@ impl ImageSelector {
                 .unwrap()
                 .map(|v| web_sys::File::from(v.unwrap()))
                 .map(File::from)
-                .partition(|f| f.raw_mime_type() == "image/png");
+                .partition(is_supported);
 
             if !others.is_empty() {
+                let names = others
+                    .iter()
+                    .map(|f| f.name())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
                 web_sys::window()
                     .expect("Failed to get window")
-                    .alert_with_message("対応していないファイルがありました。")
+                    .alert_with_message(
+                        format!("対応していないファイルがありました。\n{}", names).as_str(),
+                    )
                     .expect("Failed to alert");
             }
 
@@ -123,10 +164,46 @@ impl ImageSelector {
     }
 }
 
+/// Whether the file looks like an image the server accepts. Files without a
+/// MIME type, or named like HEIF files, are let through for the server to
+/// sniff.
+fn is_supported(file: &File) -> bool {
+    let mime_type = file.raw_mime_type();
+    let name = file.name().to_lowercase();
+
+    mime_type.is_empty()
+        || SUPPORTED_MIME_TYPES.contains(&mime_type.as_str())
+        || HEIF_EXTENSIONS.iter().any(|e| name.ends_with(e))
+}
+
+/// Width and height of the image, or `None` if the browser cannot decode it,
+/// as most cannot HEIF.
+async fn image_dimensions(file: &File) -> Option<(u32, u32)> {
+    let blob: &web_sys::Blob = file.as_ref();
+    let promise = web_sys::window()?
+        .create_image_bitmap_with_blob(blob)
+        .ok()?;
+    let bitmap: ImageBitmap = JsFuture::from(promise).await.ok()?.dyn_into().ok()?;
+    let dimensions = (bitmap.width(), bitmap.height());
+    bitmap.close();
+
+    Some(dimensions)
+}
+
 #[derive(PartialEq, Clone)]
 pub struct Image {
     pub name: String,
     pub mime_type: String,
     pub size: u64,
+    /// Width and height of a picked image, if the browser could decode it.
+    pub dimensions: Option<(u32, u32)>,
+    /// Hash of the file content, used to spot the same file picked twice.
+    pub hash: u64,
     pub bytes: Rc<RefCell<Vec<u8>>>,
 }
+
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
@@ -76,6 +76,7 @@ impl Component for ImageSorter {
                             index={index}
                             total_index={ctx.props().images.len()}
                             image={image.clone()}
+                            duplicated={ctx.props().images[..index].iter().any(|i| i.hash == image.hash)}
                             on_click_left={ctx.link().callback(OrderChangedMessage::MoveLeft)}
                             on_click_right={ctx.link().callback(OrderChangedMessage::MoveRight)}
                             on_click_remove={ctx.link().callback(OrderChangedMessage::Remove)}
@@ -1,52 +1,106 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{Cursor, Read};
 use std::ops::Deref;
 use std::rc::Rc;
 
 use anyhow::anyhow;
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
-use reqwest::header::CONTENT_TYPE;
+use reqwest::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
 use reqwest::multipart::Part;
+use reqwest::StatusCode;
+use serde::Deserialize;
 use stylist::css;
-use web_sys::HtmlInputElement;
+use web_sys::{HtmlInputElement, HtmlSelectElement, HtmlTextAreaElement};
 use yew::prelude::*;
+use zip::ZipArchive;
 
 use crate::component::button::*;
 use crate::component::image_selector::*;
 use crate::component::image_sorter::*;
 
+const REMOVED_IMAGE_INDICES_HEADER: &str = "X-Removed-Image-Indices";
+const DELETE_TOKEN_HEADER: &str = "X-Delete-Token";
+
 pub enum Msg {
     AddImage(Image),
     ImageLoading(usize),
     ImageChanged(OrderChangedMessage),
     RemoveAllImage,
-    ImageMerged(anyhow::Result<Image>),
+    ImageMerged(anyhow::Result<MergedReceipt>),
     MergeImage,
+    ShareImage,
+    ImageShared(anyhow::Result<SharedReceipt>),
+    UnshareImage,
+    ImageUnshared(anyhow::Result<()>),
+    AddComparisonGroup,
+    ClearComparisonGroups,
+    CompareImages,
+    CreateRoster,
+    OrderImages,
+    ImagesOrdered(anyhow::Result<Vec<usize>>),
     InputChanged(HtmlInputElement),
     ElementChanged(Event),
+    SelectChanged(Event),
+    TextChanged(Event),
+    RedactRegionsChanged(Event),
     BeginResultLoading,
     EndedResultLoading,
+    UploadLimitsLoaded(UploadLimits),
 }
 
 #[derive(Default)]
 pub struct MergeForm {
     images: Vec<Image>,
+    /// Screenshots of other horse girls set aside to be compared with
+    /// `images`.
+    comparison_groups: Vec<Vec<Image>>,
     loading_count: usize,
-    result_image: Option<Image>,
+    /// One image, or one per page when the receipt was split into pages.
+    result_images: Vec<Image>,
+    /// Sent back as `If-None-Match`, so that the server does not return
+    /// `result_images` again.
+    result_etag: Option<String>,
+    /// The permalink of the last receipt shared.
+    shared: Option<SharedReceipt>,
     is_loading_result: bool,
+    is_ordering: bool,
     check_options: HashMap<String, bool>,
+    text_options: HashMap<String, String>,
+    /// Rectangles to redact, each as `x,y,width,height`.
+    redact_regions: Vec<String>,
+    upload_limits: Option<UploadLimits>,
 }
 
 impl Component for MergeForm {
     type Message = Msg;
     type Properties = ();
 
-    fn create(_ctx: &Context<Self>) -> Self {
+    fn create(ctx: &Context<Self>) -> Self {
+        ctx.link().send_future(async {
+            let limits = async {
+                reqwest::get(format!("{}/config", web_sys::window().unwrap().origin()))
+                    .await?
+                    .error_for_status()?
+                    .json::<UploadLimits>()
+                    .await
+            }
+            .await;
+
+            match limits {
+                Ok(limits) => Msg::UploadLimitsLoaded(limits),
+                Err(e) => {
+                    web_sys::console::error_1(&format!("{:#?}", e).into());
+                    Msg::UploadLimitsLoaded(UploadLimits::default())
+                }
+            }
+        });
+
         Self {
             images: Vec::new(),
             loading_count: 0,
-            result_image: None,
+            result_images: Vec::new(),
             ..Default::default()
         }
     }
@@ -56,6 +110,14 @@ impl Component for MergeForm {
 
         match msg {
             Msg::AddImage(i) => {
+                if let Some(original) = self.images.iter().position(|o| o.hash == i.hash) {
+                    window
+                        .alert_with_message(
+                            format!("{}は{}枚目の画像と同じです。", i.name, original + 1).as_str(),
+                        )
+                        .expect("Failed to alert");
+                }
+
                 self.images.push(i);
                 self.loading_count -= 1;
                 true
@@ -83,73 +145,176 @@ impl Component for MergeForm {
                 true
             }
             Msg::MergeImage => {
+                if let Some(warning) = self
+                    .upload_limits
+                    .as_ref()
+                    .and_then(|l| l.check(&self.images))
+                {
+                    window
+                        .alert_with_message(warning.as_str())
+                        .expect("Failed to alert");
+                    return false;
+                }
+
                 ctx.link().send_message(Msg::BeginResultLoading);
 
-                let form = {
-                    let f = self
-                        .images
-                        .iter()
-                        .fold(reqwest::multipart::Form::new(), |f, image| {
-                            let part = Part::bytes(image.bytes.borrow().clone())
-                                .mime_str(image.mime_type.as_str())
-                                .expect("Failed to set mime type");
-                            f.part("images[]", part)
-                        });
-
-                    let f = self
-                        .check_options
-                        .iter()
-                        .fold(f, |f, (option_name, is_checked)| {
-                            let part = Part::text(is_checked.to_string())
-                                .mime_str("text/plain")
-                                .expect("Failed to set mime type");
-                            f.part(option_name.clone(), part)
-                        });
+                let form = self.build_form(&[&self.images]);
+                let etag = self.current_etag();
 
-                    f
+                ctx.link().send_future(async {
+                    Msg::ImageMerged(request_receipt("/receipts", form, etag).await)
+                });
+                true
+            }
+            Msg::ShareImage => {
+                if let Some(warning) = self
+                    .upload_limits
+                    .as_ref()
+                    .and_then(|l| l.check(&self.images))
+                {
+                    window
+                        .alert_with_message(warning.as_str())
+                        .expect("Failed to alert");
+                    return false;
+                }
+
+                ctx.link().send_message(Msg::BeginResultLoading);
+
+                let form = self.build_form(&[&self.images]);
+
+                ctx.link()
+                    .send_future(async { Msg::ImageShared(request_share(form).await) });
+                true
+            }
+            Msg::ImageShared(shared) => {
+                match shared {
+                    Ok(shared) => self.shared = Some(shared),
+                    Err(e) => {
+                        web_sys::console::error_1(&format!("{:#?}", e).into());
+                        window
+                            .alert_with_message("共有リンクを作れませんでした。")
+                            .expect("Failed to alert");
+                    }
+                }
+
+                ctx.link().send_message(Msg::EndedResultLoading);
+
+                true
+            }
+            Msg::UnshareImage => {
+                let Some(shared) = self.shared.clone() else {
+                    return false;
                 };
 
+                ctx.link()
+                    .send_future(async move { Msg::ImageUnshared(request_unshare(&shared).await) });
+                false
+            }
+            Msg::ImageUnshared(result) => {
+                match result {
+                    Ok(()) => self.shared = None,
+                    Err(e) => {
+                        web_sys::console::error_1(&format!("{:#?}", e).into());
+                        window
+                            .alert_with_message("共有を取り消せませんでした。")
+                            .expect("Failed to alert");
+                    }
+                }
+
+                true
+            }
+            Msg::AddComparisonGroup => {
+                if self.images.is_empty() {
+                    return false;
+                }
+
+                self.comparison_groups
+                    .push(std::mem::take(&mut self.images));
+                true
+            }
+            Msg::ClearComparisonGroups => {
+                self.comparison_groups.clear();
+                true
+            }
+            Msg::CompareImages => self.merge_groups(ctx, "/receipts/compare", 2),
+            Msg::CreateRoster => self.merge_groups(ctx, "/receipts/roster", 1),
+            Msg::OrderImages => {
+                if self.images.len() < 2 {
+                    return false;
+                }
+
+                self.is_ordering = true;
+
+                let form = self.build_form(&[&self.images]);
+
                 ctx.link().send_future(async {
-                    let response = reqwest::Client::new()
-                        .post(format!("{}/receipts", web_sys::window().unwrap().origin()))
-                        .multipart(form)
-                        .send()
-                        .await
-                        .map(|r| r.error_for_status());
-
-                    let result = match response {
-                        Ok(Ok(r)) => {
-                            async {
-                                let content_type = r
-                                    .headers()
-                                    .get(CONTENT_TYPE)
-                                    .ok_or(anyhow!("Not found header 'Content-Type'"))?
-                                    .to_str()
-                                    .expect("Failed to convert header to str")
-                                    .to_string();
-                                let bytes = r.bytes().await?;
-
-                                Ok(Image {
-                                    name: "".to_string(),
-                                    mime_type: content_type,
-                                    size: bytes.len() as u64,
-                                    bytes: Rc::new(RefCell::new(bytes.to_vec())),
-                                })
-                            }
-                            .await
-                        }
-                        Err(e) | Ok(Err(e)) => Err(e.into()),
-                    };
+                    let result = async {
+                        let response = reqwest::Client::new()
+                            .post(format!(
+                                "{}/receipts/order",
+                                web_sys::window().unwrap().origin()
+                            ))
+                            .multipart(form)
+                            .send()
+                            .await?
+                            .error_for_status()?
+                            .json::<OrderResponse>()
+                            .await?;
 
-                    Msg::ImageMerged(result)
+                        Ok(response.order)
+                    }
+                    .await;
+
+                    Msg::ImagesOrdered(result)
                 });
                 true
             }
+            Msg::ImagesOrdered(order) => {
+                match order {
+                    Ok(order) if order.len() == self.images.len() => {
+                        let mut images = self.images.drain(..).map(Some).collect::<Vec<_>>();
+                        self.images = order.iter().filter_map(|&i| images[i].take()).collect();
+                    }
+                    Ok(order) => {
+                        web_sys::console::error_1(&format!("Invalid order: {:?}", order).into());
+                    }
+                    Err(e) => {
+                        web_sys::console::error_1(&format!("{:#?}", e).into());
+                        window
+                            .alert_with_message("自動並べ替えに失敗しました。")
+                            .expect("Failed to alert");
+                    }
+                }
+
+                self.is_ordering = false;
+                true
+            }
             Msg::ImageMerged(i) => {
                 match i {
-                    Ok(i) => {
-                        self.result_image = Some(i);
+                    Ok(MergedReceipt {
+                        images: Some(i),
+                        removed_indices,
+                        etag,
+                    }) => {
+                        if !removed_indices.is_empty() {
+                            let removed = removed_indices
+                                .iter()
+                                .map(|i: &usize| format!("{}枚目", i + 1))
+                                .collect::<Vec<_>>()
+                                .join("、");
+                            window
+                                .alert_with_message(
+                                    format!("重複していた{}の画像を取り除きました。", removed)
+                                        .as_str(),
+                                )
+                                .expect("Failed to alert");
+                        }
+
+                        self.result_images = i;
+                        self.result_etag = etag;
                     }
+                    // The receipt shown is still current.
+                    Ok(MergedReceipt { images: None, .. }) => {}
                     Err(e) => {
                         web_sys::console::error_1(&format!("{:#?}", e).into());
                         window
@@ -171,6 +336,36 @@ impl Component for MergeForm {
                 ctx.link().send_message(Msg::InputChanged(input));
                 true
             }
+            Msg::SelectChanged(e) => {
+                let select: HtmlSelectElement =
+                    e.target_dyn_into().expect("It should select element");
+                if select.value().is_empty() {
+                    self.text_options.remove(&select.name());
+                } else {
+                    self.text_options.insert(select.name(), select.value());
+                }
+                true
+            }
+            Msg::TextChanged(e) => {
+                let input: HtmlInputElement = e.target_dyn_into().expect("It should input element");
+                if input.value().is_empty() {
+                    self.text_options.remove(&input.name());
+                } else {
+                    self.text_options.insert(input.name(), input.value());
+                }
+                true
+            }
+            Msg::RedactRegionsChanged(e) => {
+                let textarea: HtmlTextAreaElement =
+                    e.target_dyn_into().expect("It should textarea element");
+                self.redact_regions = textarea
+                    .value()
+                    .lines()
+                    .map(|l| l.trim().to_string())
+                    .filter(|l| !l.is_empty())
+                    .collect();
+                true
+            }
             Msg::BeginResultLoading => {
                 self.is_loading_result = true;
                 true
@@ -179,6 +374,10 @@ impl Component for MergeForm {
                 self.is_loading_result = false;
                 true
             }
+            Msg::UploadLimitsLoaded(limits) => {
+                self.upload_limits = Some(limits);
+                false
+            }
         }
     }
 
@@ -207,6 +406,17 @@ impl Component for MergeForm {
             padding: 1.2rem;
         "};
 
+        let advanced_options_css = css! {"
+            border-top-style: dashed;
+            border-color: #555;
+            border-width: 0.13rem;
+
+            summary {
+                padding: .8rem 1.2rem 0;
+                cursor: pointer;
+            }
+        "};
+
         let options_group_css = css! {"
             display: flex;
             justify-content: between;
@@ -216,7 +426,7 @@ impl Component for MergeForm {
                 display: flex;
                 flex-grow: 1;
             }
-            input {
+            input, select {
                 display: flex;
             }
         "};
@@ -253,27 +463,70 @@ impl Component for MergeForm {
                     images={self.images.clone()}
                     loading_count={self.loading_count}
                     on_change={ctx.link().callback(Msg::ImageChanged)}
-                    disabled={self.is_loading_result}
+                    disabled={self.is_loading_result || self.is_ordering}
                 />
-                <div class={button_container_css}>
+                <div class={button_container_css.clone()}>
                     <Button
                         on_click={ctx.link().callback(|_| Msg::MergeImage)}
                         color={Color::Confirm}
-                        disabled={self.is_loading_result}
+                        disabled={self.is_loading_result || self.is_ordering}
                     >
                         {"つなげる"}
                     </Button>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::ShareImage)}
+                        disabled={self.is_loading_result || self.is_ordering || self.images.is_empty()}
+                    >
+                        {"共有リンクを作る"}
+                    </Button>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::OrderImages)}
+                        disabled={self.is_loading_result || self.is_ordering || self.images.len() < 2}
+                    >
+                        { if self.is_ordering { "並べ替えちう..." } else { "自動並べ替え" } }
+                    </Button>
                     <Button
                         on_click={ctx.link().callback(|_| Msg::RemoveAllImage)}
                         color={Color::Error}
-                        disabled={self.is_loading_result}
+                        disabled={self.is_loading_result || self.is_ordering}
                     >
                         {"クリア"}
                     </Button>
                 </div>
+                <div class={button_container_css.clone()}>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::AddComparisonGroup)}
+                        disabled={self.is_loading_result || self.is_ordering || self.images.is_empty()}
+                    >
+                        {"比較用に取っておく"}
+                    </Button>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::CompareImages)}
+                        color={Color::Confirm}
+                        disabled={self.is_loading_result || self.is_ordering || self.comparison_groups().len() < 2}
+                    >
+                        {"並べて比較する"}
+                    </Button>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::CreateRoster)}
+                        disabled={self.is_loading_result || self.is_ordering || self.comparison_groups().is_empty()}
+                    >
+                        {"チーム表にする"}
+                    </Button>
+                    <Button
+                        on_click={ctx.link().callback(|_| Msg::ClearComparisonGroups)}
+                        color={Color::Error}
+                        disabled={self.is_loading_result || self.comparison_groups.is_empty()}
+                    >
+                        {"比較をやめる"}
+                    </Button>
+                    if !self.comparison_groups.is_empty() {
+                        <p>{format!("比較用に{}人分取っておいています。", self.comparison_groups.len())}</p>
+                    }
+                </div>
                 <div class={options_container_css}>
                     <h1>{"オプション"}</h1>
-                    <div class={options_group_container}>
+                    <div class={options_group_container.clone()}>
                         <div class={options_group_css.clone()}>
                             <label for="trim_margin" class={options_item_css.clone()}>{"余白を取り除く"}</label>
                             <input type="checkbox" name="trim_margin" id="trim_margin" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
@@ -286,14 +539,150 @@ impl Component for MergeForm {
                             <label for="trim_title" class={options_item_css.clone()}>{"\"ウマ娘詳細\"ヘッダーを取り除く"}</label>
                             <input type="checkbox" name="trim_title" id="trim_title" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
                         </div>
+                        <div class={options_group_css.clone()}>
+                            <label for="deduplicate" class={options_item_css.clone()}>{"重複した画像を取り除く"}</label>
+                            <input type="checkbox" name="deduplicate" id="deduplicate" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
+                        </div>
+                        <div class={options_group_css.clone()}>
+                            <label for="screen_type" class={options_item_css.clone()}>{"画面の種類"}</label>
+                            <select name="screen_type" id="screen_type" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::SelectChanged)}>
+                                <option value="" selected=true>{"ウマ娘詳細"}</option>
+                                <option value="auto">{"自動判別"}</option>
+                                <option value="support_card_detail">{"サポートカード詳細"}</option>
+                                <option value="factor_list">{"継承 (因子一覧)"}</option>
+                                <option value="race_history">{"レース履歴"}</option>
+                                <option value="room_match_entries">{"ルームマッチ出走表"}</option>
+                            </select>
+                        </div>
+                        <div class={options_group_css.clone()}>
+                            <label for="output_format" class={options_item_css.clone()}>{"出力形式"}</label>
+                            <select name="output_format" id="output_format" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::SelectChanged)}>
+                                <option value="png" selected=true>{"PNG"}</option>
+                                <option value="png_optimized">{"PNG (高圧縮)"}</option>
+                                <option value="webp">{"WebP (可逆)"}</option>
+                                <option value="jpeg">{"JPEG"}</option>
+                                <option value="avif">{"AVIF"}</option>
+                            </select>
+                        </div>
+                        <div class={options_group_css.clone()}>
+                            <label for="pages" class={options_item_css.clone()}>{"ページ分割"}</label>
+                            <select name="pages" id="pages" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::SelectChanged)}>
+                                <option value="" selected=true>{"しない"}</option>
+                                <option value="x">{"X (4枚まで)"}</option>
+                                <option value="instagram">{"Instagram (10枚まで)"}</option>
+                            </select>
+                        </div>
+                        <div class={options_group_css.clone()}>
+                            <label for="columns" class={options_item_css.clone()}>{"段組み"}</label>
+                            <select name="columns" id="columns" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::SelectChanged)}>
+                                <option value="1" selected=true>{"1列"}</option>
+                                <option value="2">{"2列"}</option>
+                                <option value="3">{"3列"}</option>
+                                <option value="4">{"4列"}</option>
+                            </select>
+                        </div>
                     </div>
+                    <details class={advanced_options_css.clone()}>
+                        <summary>{"キャプション・透かし"}</summary>
+                        <div class={options_group_container.clone()}>
+                            <div class={options_group_css.clone()}>
+                                <label for="caption_handle" class={options_item_css.clone()}>{"トレーナー名"}</label>
+                                <input type="text" name="caption_handle" id="caption_handle" maxlength="50" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="caption_date" class={options_item_css.clone()}>{"日付を入れる"}</label>
+                                <input type="checkbox" name="caption_date" id="caption_date" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="caption_note" class={options_item_css.clone()}>{"メモ"}</label>
+                                <input type="text" name="caption_note" id="caption_note" maxlength="200" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="watermark" class={options_item_css.clone()}>{"透かし文字"}</label>
+                                <input type="text" name="watermark" id="watermark" maxlength="50" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="roster_title" class={options_item_css.clone()}>{"チーム表のタイトル"}</label>
+                                <input type="text" name="roster_title" id="roster_title" maxlength="100" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                        </div>
+                    </details>
+                    <details class={advanced_options_css.clone()}>
+                        <summary>{"個人情報を隠す"}</summary>
+                        <div class={options_group_container.clone()}>
+                            <div class={options_group_css.clone()}>
+                                <label for="redact_personal_info" class={options_item_css.clone()}>{"トレーナー名・IDを自動で隠す"}</label>
+                                <input type="checkbox" name="redact_personal_info" id="redact_personal_info" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="redaction_style" class={options_item_css.clone()}>{"隠し方"}</label>
+                                <select name="redaction_style" id="redaction_style" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::SelectChanged)}>
+                                    <option value="" selected=true>{"黒塗り"}</option>
+                                    <option value="blur">{"ぼかし"}</option>
+                                </select>
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="redact_regions" class={options_item_css.clone()}>{"隠す範囲 (1行に1つ、x,y,幅,高さ)"}</label>
+                                <textarea name="redact_regions" id="redact_regions" rows="3" placeholder="0,120,400,60" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::RedactRegionsChanged)} />
+                            </div>
+                        </div>
+                    </details>
+                    <details class={advanced_options_css}>
+                        <summary>{"詳細設定"}</summary>
+                        <div class={options_group_container.clone()}>
+                            <div class={options_group_css.clone()}>
+                                <label for="full_resolution" class={options_item_css.clone()}>{"縮小しない"}</label>
+                                <input type="checkbox" name="full_resolution" id="full_resolution" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="scaling_threshold_pixels" class={options_item_css.clone()}>{"縮小する画素数"}</label>
+                                <input type="number" name="scaling_threshold_pixels" id="scaling_threshold_pixels" placeholder="540000" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="match_threshold" class={options_item_css.clone()}>{"つなぎ目の判定しきい値"}</label>
+                                <input type="number" name="match_threshold" id="match_threshold" placeholder="10" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="column_gutter" class={options_item_css.clone()}>{"段の間隔 (px)"}</label>
+                                <input type="number" name="column_gutter" id="column_gutter" placeholder="24" min="0" max="200" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="column_background" class={options_item_css.clone()}>{"段組みの背景色"}</label>
+                                <input type="color" name="column_background" id="column_background" value="#ffffff" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::TextChanged)} />
+                            </div>
+                            <div class={options_group_css.clone()}>
+                                <label for="debug" class={options_item_css.clone()}>{"つなぎ目を表示する (デバッグ用)"}</label>
+                                <input type="checkbox" name="debug" id="debug" class={options_item_css.clone()} onchange={ctx.link().callback(Msg::ElementChanged)} />
+                            </div>
+                        </div>
+                    </details>
                 </div>
+                if let Some(shared) = &self.shared {
+                    <div class={button_container_css}>
+                        <p>
+                            {"共有リンク: "}
+                            <a href={shared.url.clone()} target="_blank">{shared.url.clone()}</a>
+                        </p>
+                        <Button
+                            on_click={ctx.link().callback(|_| Msg::UnshareImage)}
+                            color={Color::Error}
+                        >
+                            {"共有を取り消す"}
+                        </Button>
+                    </div>
+                }
                 if !self.is_loading_result {
-                    if let Some(result_image) = &self.result_image {
-                        <div class={result_image_container_css}>
-                            <img src={format!("data:{};base64,{}", result_image.mime_type, STANDARD.encode(result_image.bytes.borrow().deref()))} />
-                        </div>
-                    }
+                    { for self.result_images.iter().enumerate().map(|(index, result_image)| {
+                        let src = format!("data:{};base64,{}", result_image.mime_type, STANDARD.encode(result_image.bytes.borrow().deref()));
+                        html! {
+                            <div class={result_image_container_css.clone()}>
+                                <img src={src.clone()} />
+                                if self.result_images.len() > 1 {
+                                    <a href={src} download={result_image.name.clone()}>{format!("{}ページ目を保存", index + 1)}</a>
+                                }
+                            </div>
+                        }
+                    }) }
                 } else {
                     <div class={result_image_container_css}>
                         <p>{"がっちゃんこちう..."}</p>
@@ -308,4 +697,298 @@ impl Component for MergeForm {
     }
 }
 
-impl MergeForm {}
+impl MergeForm {
+    /// The groups set aside for comparison, followed by the images being
+    /// edited if there are any.
+    fn comparison_groups(&self) -> Vec<&Vec<Image>> {
+        self.comparison_groups
+            .iter()
+            .chain(Some(&self.images).filter(|i| !i.is_empty()))
+            .collect()
+    }
+
+    /// Sends every group of images to be stitched separately and combined.
+    fn merge_groups(&self, ctx: &Context<Self>, path: &'static str, min_groups: usize) -> bool {
+        let groups = self.comparison_groups();
+        if groups.len() < min_groups {
+            return false;
+        }
+
+        let images = groups
+            .iter()
+            .flat_map(|g| g.iter().cloned())
+            .collect::<Vec<_>>();
+        if let Some(warning) = self.upload_limits.as_ref().and_then(|l| l.check(&images)) {
+            web_sys::window()
+                .expect("Failed to get window")
+                .alert_with_message(warning.as_str())
+                .expect("Failed to alert");
+            return false;
+        }
+
+        ctx.link().send_message(Msg::BeginResultLoading);
+
+        let form = self.build_form(&groups);
+        let etag = self.current_etag();
+
+        ctx.link()
+            .send_future(async move { Msg::ImageMerged(request_receipt(path, form, etag).await) });
+        true
+    }
+
+    /// The ETag of the receipt shown, if any.
+    fn current_etag(&self) -> Option<String> {
+        self.result_etag
+            .clone()
+            .filter(|_| !self.result_images.is_empty())
+    }
+
+    /// Builds the form for the images of every group, adding the group of
+    /// each image when there are more than one.
+    fn build_form(&self, groups: &[&Vec<Image>]) -> reqwest::multipart::Form {
+        let f = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, images)| images.iter().map(move |image| (group, image)))
+            .fold(reqwest::multipart::Form::new(), |f, (group, image)| {
+                let part = Part::bytes(image.bytes.borrow().clone())
+                    .mime_str(image.mime_type.as_str())
+                    .expect("Failed to set mime type");
+                let f = f.part("images[]", part);
+
+                if groups.len() > 1 {
+                    f.text("groups[]", group.to_string())
+                } else {
+                    f
+                }
+            });
+
+        let f = self
+            .check_options
+            .iter()
+            .fold(f, |f, (option_name, is_checked)| {
+                let part = Part::text(is_checked.to_string())
+                    .mime_str("text/plain")
+                    .expect("Failed to set mime type");
+                f.part(option_name.clone(), part)
+            });
+
+        let f = self.text_options.iter().fold(f, |f, (option_name, value)| {
+            let part = Part::text(value.clone())
+                .mime_str("text/plain")
+                .expect("Failed to set mime type");
+            f.part(option_name.clone(), part)
+        });
+
+        self.redact_regions.iter().fold(f, |f, region| {
+            let part = Part::text(region.clone())
+                .mime_str("text/plain")
+                .expect("Failed to set mime type");
+            f.part("redact_regions[]", part)
+        })
+    }
+}
+
+/// Posts the form and reads the returned receipt, along with the indices of
+/// the images dropped as duplicates. With the ETag of the receipt shown, the
+/// server only returns a receipt if it is a different one.
+async fn request_receipt(
+    path: &str,
+    form: reqwest::multipart::Form,
+    etag: Option<String>,
+) -> anyhow::Result<MergedReceipt> {
+    let mut request = reqwest::Client::new()
+        .post(format!("{}{}", web_sys::window().unwrap().origin(), path))
+        .multipart(form);
+    if let Some(etag) = &etag {
+        request = request.header(IF_NONE_MATCH, etag.as_str());
+    }
+    let response = request.send().await?.error_for_status()?;
+
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(MergedReceipt {
+            images: None,
+            removed_indices: Vec::new(),
+            etag,
+        });
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .ok_or(anyhow!("Not found header 'Content-Type'"))?
+        .to_str()
+        .expect("Failed to convert header to str")
+        .to_string();
+    let removed_indices = response
+        .headers()
+        .get(REMOVED_IMAGE_INDICES_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').filter_map(|i| i.parse().ok()).collect())
+        .unwrap_or_default();
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string());
+    let bytes = response.bytes().await?;
+
+    let images = if content_type == "application/zip" {
+        unzip_pages(&bytes)?
+    } else {
+        vec![Image {
+            name: "".to_string(),
+            mime_type: content_type,
+            size: bytes.len() as u64,
+            dimensions: None,
+            hash: content_hash(&bytes),
+            bytes: Rc::new(RefCell::new(bytes.to_vec())),
+        }]
+    };
+
+    Ok(MergedReceipt {
+        images: Some(images),
+        removed_indices,
+        etag,
+    })
+}
+
+/// Stitches the receipt and stores it behind a permalink.
+async fn request_share(form: reqwest::multipart::Form) -> anyhow::Result<SharedReceipt> {
+    let shared = reqwest::Client::new()
+        .post(format!(
+            "{}/receipts/share",
+            web_sys::window().unwrap().origin()
+        ))
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(shared)
+}
+
+async fn request_unshare(shared: &SharedReceipt) -> anyhow::Result<()> {
+    reqwest::Client::new()
+        .delete(format!(
+            "{}/r/{}",
+            web_sys::window().unwrap().origin(),
+            shared.id
+        ))
+        .header(DELETE_TOKEN_HEADER, shared.delete_token.as_str())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Reads the pages of a receipt returned as a ZIP file, in order.
+fn unzip_pages(bytes: &[u8]) -> anyhow::Result<Vec<Image>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    let mut pages = Vec::with_capacity(archive.len());
+
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mime_type = match file.name().rsplit('.').next() {
+            Some("jpg") => "image/jpeg",
+            Some("webp") => "image/webp",
+            Some("avif") => "image/avif",
+            _ => "image/png",
+        };
+
+        pages.push(Image {
+            name: file.name().to_string(),
+            mime_type: mime_type.to_string(),
+            size: bytes.len() as u64,
+            dimensions: None,
+            hash: content_hash(&bytes),
+            bytes: Rc::new(RefCell::new(bytes)),
+        });
+    }
+
+    Ok(pages)
+}
+
+/// A receipt returned by the server.
+pub struct MergedReceipt {
+    /// `None` if the server answered that the receipt shown is still
+    /// current.
+    images: Option<Vec<Image>>,
+    removed_indices: Vec<usize>,
+    etag: Option<String>,
+}
+
+/// A receipt stored by `/receipts/share`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharedReceipt {
+    id: String,
+    url: String,
+    delete_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderResponse {
+    order: Vec<usize>,
+}
+
+/// Upload budgets served by the `/config` endpoint.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UploadLimits {
+    pub max_images: Option<usize>,
+    pub max_image_bytes: Option<u64>,
+    pub max_request_bytes: Option<u64>,
+    pub max_total_pixels: Option<u64>,
+}
+
+impl UploadLimits {
+    /// Returns a warning message if the images would be rejected by the server.
+    fn check(&self, images: &[Image]) -> Option<String> {
+        if let Some(max_images) = self.max_images {
+            if images.len() > max_images {
+                return Some(format!("画像は{}枚までです。", max_images));
+            }
+        }
+
+        if let Some(max_image_bytes) = self.max_image_bytes {
+            if let Some(index) = images.iter().position(|i| i.size > max_image_bytes) {
+                return Some(format!(
+                    "{}枚目の画像が大きすぎます。({:.1} MBまで)",
+                    index + 1,
+                    max_image_bytes as f64 / 1000000.0
+                ));
+            }
+        }
+
+        if let Some(max_request_bytes) = self.max_request_bytes {
+            if images.iter().map(|i| i.size).sum::<u64>() > max_request_bytes {
+                return Some(format!(
+                    "画像の合計サイズが大きすぎます。({:.1} MBまで)",
+                    max_request_bytes as f64 / 1000000.0
+                ));
+            }
+        }
+
+        // Images the browser could not decode are left to the server.
+        if let Some(max_total_pixels) = self.max_total_pixels {
+            let total_pixels = images
+                .iter()
+                .filter_map(|i| i.dimensions)
+                .map(|(width, height)| width as u64 * height as u64)
+                .sum::<u64>();
+            if total_pixels > max_total_pixels {
+                return Some(format!(
+                    "画像の合計画素数が大きすぎます。({:.1} メガピクセルまで)",
+                    max_total_pixels as f64 / 1000000.0
+                ));
+            }
+        }
+
+        None
+    }
+}
@@ -21,6 +21,8 @@ pub struct Props {
     pub index: usize,
     pub total_index: usize,
     #[prop_or_default]
+    pub duplicated: bool,
+    #[prop_or_default]
     pub class: Classes,
     pub on_click_left: Callback<usize>,
     pub on_click_right: Callback<usize>,
@@ -124,6 +126,9 @@ impl Component for SortingImage {
                 font-size: .8rem;
                 line-height: 1em;
             }
+            .duplicated {
+                color: #f0ad4e;
+            }
         "};
 
         let image_container_css = css! {"
@@ -151,6 +156,9 @@ impl Component for SortingImage {
                 <div class={header_css}>
                     <h1>{ctx.props().index + 1} {"枚目"}</h1>
                     <p>{format!("{:.2} MB", size_mega_byte)}</p>
+                    if ctx.props().duplicated {
+                        <p class="duplicated">{"重複しています"}</p>
+                    }
                 </div>
                 <div class={image_container_css}>
                     // TODO: コンポーネント化する。Base64Image みたいな。エンコードもそっちでやる
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Downloads the fonts embedded by src/caption.rs. Noto Sans JP is licensed
+# under the SIL Open Font License 1.1.
+set -eu
+
+cd "$(dirname "$0")"
+
+BASE_URL="https://github.com/notofonts/noto-cjk/raw/Sans2.004/Sans/SubsetOTF/JP"
+
+for weight in Regular Bold; do
+  if [ ! -f "NotoSansJP-$weight.otf" ]; then
+    curl -fsSL -o "NotoSansJP-$weight.otf" "$BASE_URL/NotoSansJP-$weight.otf"
+  fi
+done
@@ -0,0 +1,14 @@
+#!/bin/sh
+# Downloads the Tesseract model used by src/ocr.rs. The models are licensed
+# under the Apache License 2.0.
+set -eu
+
+cd "$(dirname "$0")"
+
+BASE_URL="https://github.com/tesseract-ocr/tessdata_fast/raw/4.1.0"
+
+for language in jpn; do
+  if [ ! -f "$language.traineddata" ]; then
+    curl -fsSL -o "$language.traineddata" "$BASE_URL/$language.traineddata"
+  fi
+done
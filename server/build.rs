@@ -0,0 +1,20 @@
+use std::path::Path;
+
+/// Fonts embedded by `src/caption.rs`. They are not checked in.
+const FONTS: [&str; 2] = [
+    "assets/fonts/NotoSansJP-Regular.otf",
+    "assets/fonts/NotoSansJP-Bold.otf",
+];
+
+fn main() {
+    for font in FONTS {
+        println!("cargo:rerun-if-changed={}", font);
+
+        if !Path::new(font).is_file() {
+            panic!(
+                "{} is missing. Run server/assets/fonts/fetch.sh to download it before building.",
+                font
+            );
+        }
+    }
+}
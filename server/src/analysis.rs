@@ -0,0 +1,138 @@
+use image::DynamicImage;
+use serde::Serialize;
+use uma_details_utility::image::ImageMatrix;
+
+use crate::detail::{self, StitchOptions};
+use crate::error::ApiError;
+use crate::screen::{Layout, ScrollMatch, ScrollProfiles};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalysisStatus {
+    Ok,
+    Failed,
+    /// Not analyzed because an earlier screenshot already failed.
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageAnalysis {
+    pub index: usize,
+    pub file_name: String,
+    pub width: u32,
+    pub height: u32,
+    pub status: AnalysisStatus,
+    /// Width of the stitched output divided by the width of this screenshot.
+    pub scaling_factor: Option<f64>,
+    /// Rows this screenshot added to the stitched output.
+    pub appended_height: Option<u32>,
+    /// Rows of this screenshot, in output scale, that overlapped the previous
+    /// one and were dropped.
+    pub overlap_height: Option<u32>,
+    /// Header, scroll area and close button found in this screenshot, in its
+    /// own pixels.
+    pub layout: Option<Layout>,
+    /// How this screenshot's scroll area lines up with the previous one's,
+    /// measured apart from the stitcher.
+    pub scroll_match: Option<ScrollMatch>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReceiptAnalysis {
+    pub images: Vec<ImageAnalysis>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Stitches every prefix of `images` to find out how each screenshot was
+/// joined and which one, if any, breaks the merge.
+///
+/// This runs one stitch per screenshot, each over every screenshot before
+/// it, so callers must keep `images` to `UploadLimits::max_analyzed_images`.
+/// Also returns the image stitched from the longest prefix that succeeded.
+pub fn analyze(
+    images: &[DynamicImage],
+    file_names: &[String],
+    options: &StitchOptions,
+) -> (ReceiptAnalysis, Option<DynamicImage>) {
+    let mut analyses = Vec::with_capacity(images.len());
+    let mut previous: Option<(u32, u32)> = None;
+    let mut last_stitched = None;
+    let mut failed = false;
+
+    let layouts = images
+        .iter()
+        .map(|i| options.screen_type.find_layout(i))
+        .collect::<Vec<_>>();
+    let scroll_profiles = ScrollProfiles::new(images, options);
+
+    for (index, (image, file_name)) in images.iter().zip(file_names).enumerate() {
+        let scroll_match = match (index.checked_sub(1), &scroll_profiles) {
+            (Some(previous), Some(profiles)) => profiles.compare(previous, index),
+            _ => None,
+        };
+        let mut analysis = ImageAnalysis {
+            index,
+            file_name: file_name.clone(),
+            width: image.width(),
+            height: image.height(),
+            status: AnalysisStatus::Skipped,
+            scaling_factor: None,
+            appended_height: None,
+            overlap_height: None,
+            layout: layouts[index],
+            scroll_match,
+            error: None,
+        };
+
+        if failed {
+            analyses.push(analysis);
+            continue;
+        }
+
+        let result = detail::stitch(&images[..=index], options)
+            .and_then(|detail| Ok(detail.convert_to_image()?));
+
+        match result {
+            Ok(stitched) => {
+                let scaling_factor = stitched.width() as f64 / image.width() as f64;
+                let previous_height = previous.map_or(0, |(_, height)| height);
+                let appended_height = stitched.height().saturating_sub(previous_height);
+                let scaled_height = (image.height() as f64 * scaling_factor).round() as u32;
+
+                analysis.status = AnalysisStatus::Ok;
+                analysis.scaling_factor = Some(scaling_factor);
+                analysis.appended_height = Some(appended_height);
+                if index > 0 {
+                    analysis.overlap_height = Some(scaled_height.saturating_sub(appended_height));
+                }
+
+                previous = Some((stitched.width(), stitched.height()));
+                last_stitched = Some(stitched);
+            }
+            Err(e) => {
+                analysis.status = AnalysisStatus::Failed;
+                analysis.error = Some(describe_error(&e));
+                failed = true;
+            }
+        }
+
+        analyses.push(analysis);
+    }
+
+    let analysis = ReceiptAnalysis {
+        images: analyses,
+        width: previous.filter(|_| !failed).map(|(width, _)| width),
+        height: previous.filter(|_| !failed).map(|(_, height)| height),
+    };
+
+    (analysis, last_stitched)
+}
+
+pub fn describe_error(error: &ApiError) -> String {
+    match error {
+        ApiError::ImageProcessFailed { source } => source.to_string(),
+        e => e.to_string(),
+    }
+}
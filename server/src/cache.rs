@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::{error, info};
+
+use crate::config::CacheConfig;
+use crate::controller::receipt::EncodedReceipt;
+
+/// Receipts generated recently, by a key identifying the uploads and
+/// options they were generated from, so that the same request is not
+/// stitched twice. The most recently used ones are kept in memory and, if a
+/// directory is configured, more of them on disk.
+///
+/// Caching is best effort: failing to read or write the disk is logged and
+/// treated as a miss.
+pub struct ResultCache {
+    memory: Mutex<Lru<Arc<EncodedReceipt>>>,
+    disk: Option<DiskCache>,
+}
+
+impl ResultCache {
+    pub fn new(config: &CacheConfig) -> anyhow::Result<Self> {
+        let disk = match &config.directory {
+            Some(directory) => Some(DiskCache::open(directory, config.max_disk_bytes)?),
+            None => None,
+        };
+
+        Ok(Self {
+            memory: Mutex::new(Lru::new(config.max_entries, config.max_bytes)),
+            disk,
+        })
+    }
+
+    /// May block on reading the disk.
+    pub fn get(&self, key: &str) -> Option<Arc<EncodedReceipt>> {
+        if let Some(receipt) = self.memory.lock().unwrap().get(key) {
+            return Some(receipt);
+        }
+
+        let receipt = Arc::new(self.disk.as_ref()?.get(key)?);
+        self.remember(key, receipt.clone());
+
+        Some(receipt)
+    }
+
+    /// May block on writing the disk.
+    pub fn put(&self, key: &str, receipt: Arc<EncodedReceipt>) {
+        self.remember(key, receipt.clone());
+
+        if let Some(disk) = &self.disk {
+            disk.put(key, &receipt);
+        }
+    }
+
+    fn remember(&self, key: &str, receipt: Arc<EncodedReceipt>) {
+        let size = receipt.bytes.len() as u64;
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), receipt, size);
+    }
+}
+
+/// Keeps each receipt as `{key}` under a directory: its metadata as a line
+/// of JSON followed by the body.
+struct DiskCache {
+    directory: PathBuf,
+    /// Sizes of the files, to evict the least recently used ones.
+    index: Mutex<Lru<()>>,
+}
+
+impl DiskCache {
+    /// Indexes the receipts left by a previous run, oldest first.
+    fn open(directory: &str, max_bytes: u64) -> anyhow::Result<Self> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory)?;
+
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !metadata.is_file() {
+                continue;
+            }
+
+            if is_key(&name) {
+                entries.push((
+                    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    name,
+                    metadata.len(),
+                ));
+            } else if name.strip_suffix(".partial").map_or(false, is_key) {
+                // Left half written.
+                fs::remove_file(entry.path())?;
+            }
+        }
+        entries.sort();
+
+        let cache = Self {
+            directory,
+            index: Mutex::new(Lru::new(usize::MAX, max_bytes)),
+        };
+        let count = entries.len();
+        for (_, key, size) in entries {
+            let evicted = cache.index.lock().unwrap().insert(key, (), size);
+            cache.remove_files(&evicted);
+        }
+        info!("Found {} cached receipts on disk", count);
+
+        Ok(cache)
+    }
+
+    fn get(&self, key: &str) -> Option<EncodedReceipt> {
+        self.index.lock().unwrap().get(key)?;
+
+        let read = fs::read(self.directory.join(key))
+            .map_err(anyhow::Error::from)
+            .and_then(|file| {
+                let split = file
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .ok_or_else(|| anyhow::anyhow!("No metadata"))?;
+                let mut receipt: EncodedReceipt = serde_json::from_slice(&file[..split])?;
+                receipt.bytes = file[split + 1..].to_vec().into();
+
+                Ok(receipt)
+            });
+
+        match read {
+            Ok(receipt) => Some(receipt),
+            Err(e) => {
+                error!("Failed to read cached receipt {}: {:?}", key, e);
+                self.index.lock().unwrap().remove(key);
+                self.remove_files(&[key.to_string()]);
+                None
+            }
+        }
+    }
+
+    fn put(&self, key: &str, receipt: &EncodedReceipt) {
+        let path = self.directory.join(key);
+        let partial = self.directory.join(format!("{}.partial", key));
+        let write = serde_json::to_vec(receipt)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut file| {
+                file.push(b'\n');
+                file.extend_from_slice(&receipt.bytes);
+                fs::write(&partial, &file)?;
+                fs::rename(&partial, &path)?;
+
+                Ok(file.len() as u64)
+            });
+
+        match write {
+            Ok(size) => {
+                let evicted = self.index.lock().unwrap().insert(key.to_string(), (), size);
+                self.remove_files(&evicted);
+            }
+            Err(e) => {
+                error!("Failed to cache receipt {} on disk: {:?}", key, e);
+                let _ = fs::remove_file(partial);
+            }
+        }
+    }
+
+    fn remove_files(&self, keys: &[String]) {
+        for key in keys {
+            match fs::remove_file(self.directory.join(key)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    error!("Failed to remove cached receipt {}: {:?}", key, e)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Keys are SHA-256 digests in hex.
+fn is_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Evicts the least recently used entries once there are more than
+/// `max_entries` of them or their sizes add up to more than `max_bytes`.
+struct Lru<T> {
+    entries: HashMap<String, LruEntry<T>>,
+    clock: u64,
+    bytes: u64,
+    max_entries: usize,
+    max_bytes: u64,
+}
+
+struct LruEntry<T> {
+    value: T,
+    size: u64,
+    last_used: u64,
+}
+
+impl<T: Clone> Lru<T> {
+    fn new(max_entries: usize, max_bytes: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            clock: 0,
+            bytes: 0,
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<T> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+
+        Some(entry.value.clone())
+    }
+
+    /// Returns the keys evicted to make room, which include `key` itself if
+    /// it does not fit at all.
+    fn insert(&mut self, key: String, value: T, size: u64) -> Vec<String> {
+        if self.max_entries == 0 || size > self.max_bytes {
+            self.remove(&key);
+            return vec![key];
+        }
+
+        self.clock += 1;
+        let entry = LruEntry {
+            value,
+            size,
+            last_used: self.clock,
+        };
+        if let Some(replaced) = self.entries.insert(key, entry) {
+            self.bytes -= replaced.size;
+        }
+        self.bytes += size;
+
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.max_entries || self.bytes > self.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt(bytes: &[u8]) -> EncodedReceipt {
+        let mut receipt: EncodedReceipt = serde_json::from_str(
+            r#"{"mime":"image/png","file_name":"receipt.png","attachment":false,"removed_indices":[]}"#,
+        )
+        .unwrap();
+        receipt.bytes = bytes.to_vec().into();
+        receipt
+    }
+
+    fn key(n: u8) -> String {
+        format!("{:064x}", n)
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_entries_over_the_count() {
+        let mut lru = Lru::new(2, u64::MAX);
+        assert!(lru.insert("a".to_string(), 1, 1).is_empty());
+        assert!(lru.insert("b".to_string(), 2, 1).is_empty());
+        assert_eq!(lru.get("a"), Some(1));
+
+        assert_eq!(lru.insert("c".to_string(), 3, 1), ["b"]);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(1));
+        assert_eq!(lru.get("c"), Some(3));
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_entries_over_the_size() {
+        let mut lru = Lru::new(usize::MAX, 10);
+        lru.insert("a".to_string(), 1, 4);
+        lru.insert("b".to_string(), 2, 4);
+
+        assert_eq!(lru.insert("c".to_string(), 3, 4), ["a"]);
+        assert_eq!(lru.bytes, 8);
+
+        // Replacing an entry only counts its new size.
+        assert!(lru.insert("b".to_string(), 2, 6).is_empty());
+        assert_eq!(lru.bytes, 10);
+
+        // An entry larger than the cache is not kept at all.
+        assert_eq!(lru.insert("d".to_string(), 4, 11), ["d"]);
+        assert_eq!(lru.get("d"), None);
+        assert_eq!(lru.bytes, 10);
+    }
+
+    #[test]
+    fn disk_cache_round_trips_receipts_across_restarts() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let path = directory.to_str().unwrap();
+
+        let disk = DiskCache::open(path, u64::MAX).unwrap();
+        assert!(disk.get(&key(1)).is_none());
+        disk.put(&key(1), &receipt(b"png"));
+        assert_eq!(&disk.get(&key(1)).unwrap().bytes[..], b"png");
+
+        fs::write(directory.join(format!("{}.partial", key(2))), "").unwrap();
+        let reopened = DiskCache::open(path, u64::MAX).unwrap();
+        let cached = reopened.get(&key(1)).unwrap();
+        assert_eq!(&cached.bytes[..], b"png");
+        assert!(!directory.join(format!("{}.partial", key(2))).exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn disk_cache_removes_the_oldest_files_over_the_size_cap() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let disk = DiskCache::open(directory.to_str().unwrap(), 300).unwrap();
+
+        disk.put(&key(1), &receipt(&[1; 100]));
+        disk.put(&key(2), &receipt(&[2; 100]));
+
+        assert!(disk.get(&key(1)).is_none());
+        assert!(!directory.join(key(1)).exists());
+        assert_eq!(&disk.get(&key(2)).unwrap().bytes[..], &[2; 100]);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
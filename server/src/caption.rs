@@ -0,0 +1,258 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use once_cell::sync::Lazy;
+use rusttype::{point, Font, Scale};
+
+use crate::error::ApiError;
+
+/// Noto Sans JP, fetched by `assets/fonts/fetch.sh` before building.
+static FONT_REGULAR: &[u8] = include_bytes!("../assets/fonts/NotoSansJP-Regular.otf");
+static FONT_BOLD: &[u8] = include_bytes!("../assets/fonts/NotoSansJP-Bold.otf");
+
+/// Parsed on first use and shared by every receipt.
+static REGULAR: Lazy<Font<'static>> =
+    Lazy::new(|| Font::try_from_bytes(FONT_REGULAR).expect("Bundled font is invalid"));
+static BOLD: Lazy<Font<'static>> =
+    Lazy::new(|| Font::try_from_bytes(FONT_BOLD).expect("Bundled font is invalid"));
+
+const MAX_HANDLE_LENGTH: usize = 50;
+const MAX_NOTE_LENGTH: usize = 200;
+const MAX_WATERMARK_LENGTH: usize = 50;
+
+const BAND_COLOR: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const TEXT_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const DATE_COLOR: Rgba<u8> = Rgba([180, 180, 180, 255]);
+const WATERMARK_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const WATERMARK_OPACITY: f32 = 0.25;
+const LINE_SPACING: f32 = 1.4;
+
+/// A caption band under the receipt and a watermark across it.
+#[derive(Debug, Clone, Default)]
+pub struct Decoration {
+    pub handle: Option<String>,
+    /// Formatted date, or `None` to leave it out.
+    pub date: Option<String>,
+    pub note: Option<String>,
+    pub watermark: Option<String>,
+}
+
+impl Decoration {
+    /// Parses the `caption_handle`, `caption_date`, `caption_note` and
+    /// `watermark` form fields. Returns `None` if none of them are set.
+    pub fn from_form(
+        handle: Option<String>,
+        show_date: bool,
+        note: Option<String>,
+        watermark: Option<String>,
+    ) -> Result<Option<Self>, ApiError> {
+        let handle = non_empty("caption_handle", handle, MAX_HANDLE_LENGTH)?;
+        let note = non_empty("caption_note", note, MAX_NOTE_LENGTH)?;
+        let watermark = non_empty("watermark", watermark, MAX_WATERMARK_LENGTH)?;
+        let date = show_date.then(|| chrono::Local::now().format("%Y/%m/%d").to_string());
+
+        if handle.is_none() && date.is_none() && note.is_none() && watermark.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            handle,
+            date,
+            note,
+            watermark,
+        }))
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (regular, bold) = (regular_font(), bold_font());
+
+        let mut image = image.to_rgba8();
+        if let Some(watermark) = &self.watermark {
+            draw_watermark(&mut image, bold, watermark);
+        }
+
+        if self.handle.is_none() && self.date.is_none() && self.note.is_none() {
+            return DynamicImage::ImageRgba8(image);
+        }
+
+        DynamicImage::ImageRgba8(self.append_band(&image, regular, bold))
+    }
+
+    /// Adds the handle and date on the first line and the note, wrapped to
+    /// the width of the receipt, below them.
+    fn append_band(&self, image: &RgbaImage, regular: &Font, bold: &Font) -> RgbaImage {
+        let width = image.width();
+        let font_size = (width as f32 / 28.0).clamp(16.0, 64.0);
+        let scale = Scale::uniform(font_size);
+        let padding = font_size * 0.8;
+        let line_height = font_size * LINE_SPACING;
+        let text_width = width as f32 - padding * 2.0;
+
+        let note_lines = self
+            .note
+            .as_deref()
+            .map_or_else(Vec::new, |note| wrap(regular, scale, note, text_width));
+        let has_first_line = self.handle.is_some() || self.date.is_some();
+        let line_count = note_lines.len() + has_first_line as usize;
+        let band_height = (padding * 2.0 + line_height * line_count as f32).ceil() as u32;
+
+        let mut canvas = RgbaImage::from_pixel(width, image.height() + band_height, BAND_COLOR);
+        image::imageops::replace(&mut canvas, image, 0, 0);
+
+        let mut y = image.height() as f32 + padding;
+        if has_first_line {
+            if let Some(handle) = &self.handle {
+                draw_text(
+                    &mut canvas,
+                    bold,
+                    scale,
+                    padding,
+                    y,
+                    TEXT_COLOR,
+                    1.0,
+                    handle,
+                );
+            }
+            if let Some(date) = &self.date {
+                let x = width as f32 - padding - text_advance(regular, scale, date);
+                draw_text(&mut canvas, regular, scale, x, y, DATE_COLOR, 1.0, date);
+            }
+            y += line_height;
+        }
+        for line in note_lines {
+            draw_text(
+                &mut canvas,
+                regular,
+                scale,
+                padding,
+                y,
+                TEXT_COLOR,
+                1.0,
+                &line,
+            );
+            y += line_height;
+        }
+
+        canvas
+    }
+}
+
+pub fn regular_font() -> &'static Font<'static> {
+    &REGULAR
+}
+
+pub fn bold_font() -> &'static Font<'static> {
+    &BOLD
+}
+
+/// Repeats the watermark down the middle of the receipt.
+fn draw_watermark(image: &mut RgbaImage, font: &Font, text: &str) {
+    let font_size = (image.width() as f32 / 12.0).max(16.0);
+    let scale = Scale::uniform(font_size);
+    let x = (image.width() as f32 - text_advance(font, scale, text)) / 2.0;
+    let step = font_size * 8.0;
+
+    let mut y = step / 2.0;
+    while y < image.height() as f32 {
+        draw_text(
+            image,
+            font,
+            scale,
+            x,
+            y,
+            WATERMARK_COLOR,
+            WATERMARK_OPACITY,
+            text,
+        );
+        y += step;
+    }
+}
+
+/// Draws a line of text with its top left corner at `x`, `y`.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_text(
+    image: &mut RgbaImage,
+    font: &Font,
+    scale: Scale,
+    x: f32,
+    y: f32,
+    color: Rgba<u8>,
+    opacity: f32,
+    text: &str,
+) {
+    let ascent = font.v_metrics(scale).ascent;
+    let (width, height) = image.dimensions();
+
+    for glyph in font.layout(text, scale, point(x, y + ascent)) {
+        let Some(bounds) = glyph.pixel_bounding_box() else {
+            continue;
+        };
+
+        glyph.draw(|gx, gy, coverage| {
+            let (px, py) = (bounds.min.x + gx as i32, bounds.min.y + gy as i32);
+            if px < 0 || py < 0 || px as u32 >= width || py as u32 >= height {
+                return;
+            }
+
+            let alpha = coverage * opacity;
+            let pixel = image.get_pixel_mut(px as u32, py as u32);
+            for channel in 0..3 {
+                pixel[channel] = (pixel[channel] as f32 * (1.0 - alpha)
+                    + color[channel] as f32 * alpha)
+                    .round() as u8;
+            }
+        });
+    }
+}
+
+pub fn text_advance(font: &Font, scale: Scale, text: &str) -> f32 {
+    text.chars()
+        .map(|c| font.glyph(c).scaled(scale).h_metrics().advance_width)
+        .sum()
+}
+
+/// Breaks text into lines no wider than `max_width`. Japanese has no spaces
+/// between words, so lines may break after any character.
+fn wrap(font: &Font, scale: Scale, text: &str, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        let mut line_width = 0.0;
+
+        for c in paragraph.chars() {
+            let advance = font.glyph(c).scaled(scale).h_metrics().advance_width;
+            if line_width + advance > max_width && !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0.0;
+            }
+            line.push(c);
+            line_width += advance;
+        }
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// Trims a text form field, returning `None` if it is empty.
+pub fn non_empty(
+    name: &str,
+    value: Option<String>,
+    max_length: usize,
+) -> Result<Option<String>, ApiError> {
+    let Some(value) = value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+    else {
+        return Ok(None);
+    };
+
+    if value.chars().count() > max_length {
+        return Err(ApiError::InvalidParameter {
+            message: format!("{} must be at most {} characters", name, max_length),
+            sensitive_message: None,
+        });
+    }
+
+    Ok(Some(value))
+}
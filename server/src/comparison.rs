@@ -0,0 +1,246 @@
+use std::ops::RangeInclusive;
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::error::ApiError;
+use crate::layout;
+
+pub const MAX_RECEIPTS: usize = 4;
+/// Size every section is shrunk to before comparing it.
+const FINGERPRINT_WIDTH: u32 = 64;
+const FINGERPRINT_HEIGHT: u32 = 8;
+/// Largest mean difference of fingerprint pixels for two sections to count
+/// as the same header.
+const MAX_SECTION_DIFFERENCE: u32 = 8;
+/// Largest difference in height, relative to the taller one, for two sections
+/// to count as the same header.
+const MAX_HEIGHT_DIFFERENCE_RATIO: f32 = 0.1;
+
+/// Lays out receipts of different horse girls side by side.
+#[derive(Debug, Clone, Copy)]
+pub struct Comparison {
+    /// Space between receipts and around the edges, in pixels.
+    pub gutter: u32,
+    pub background: Rgba<u8>,
+}
+
+impl Comparison {
+    /// Parses the `column_gutter` and `column_background` form fields, which
+    /// are shared with the column layout.
+    pub fn from_form(gutter: Option<u32>, background: Option<&str>) -> Result<Self, ApiError> {
+        Ok(Self {
+            gutter: layout::parse_gutter(gutter)?,
+            background: layout::parse_background(background)?,
+        })
+    }
+
+    /// Shrinks the receipts to the narrowest one and lays them out left to
+    /// right. Sections that look the same in every receipt, such as the stats
+    /// labels and the skill and factor headers, are moved down to the same
+    /// height so that the receipts can be read across.
+    pub fn compose(&self, receipts: &[DynamicImage]) -> DynamicImage {
+        let width = receipts.iter().map(|r| r.width()).min().unwrap_or(0);
+        let receipts = receipts
+            .iter()
+            .map(|r| {
+                if r.width() == width {
+                    r.clone()
+                } else {
+                    let height = (r.height() as u64 * width as u64 / r.width() as u64) as u32;
+                    r.resize_exact(width, height, FilterType::Lanczos3)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let sections = receipts.iter().map(|r| sections(r)).collect::<Vec<_>>();
+        let anchors = common_sections(&sections);
+
+        // The top of each receipt, of the anchored sections in it and its
+        // bottom.
+        let stops = receipts
+            .iter()
+            .zip(&sections)
+            .enumerate()
+            .map(|(index, (receipt, sections))| {
+                [0].into_iter()
+                    .chain(anchors.iter().map(|a| sections[a[index]].top))
+                    .chain([receipt.height()])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // Where each stop is drawn, the same for every receipt. The last one
+        // is the height of the tallest receipt after padding.
+        let mut targets = vec![0];
+        for stop in 1..anchors.len() + 2 {
+            let previous = *targets.last().unwrap();
+            let target = stops
+                .iter()
+                .map(|s| previous + s[stop] - s[stop - 1])
+                .max()
+                .unwrap_or(previous);
+            targets.push(target);
+        }
+        let height = *targets.last().unwrap();
+
+        let count = receipts.len() as u32;
+        let mut canvas = RgbaImage::from_pixel(
+            count * width + (count + 1) * self.gutter,
+            height + 2 * self.gutter,
+            self.background,
+        );
+
+        for (index, (receipt, stops)) in receipts.iter().zip(&stops).enumerate() {
+            let x = self.gutter + index as u32 * (width + self.gutter);
+            for (stop, target) in stops.windows(2).zip(&targets) {
+                if stop[1] > stop[0] {
+                    let piece = receipt
+                        .view(0, stop[0], width, stop[1] - stop[0])
+                        .to_image();
+                    let y = self.gutter + target;
+                    imageops::overlay(&mut canvas, &piece, x as i64, y as i64);
+                }
+            }
+        }
+
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Checks the `groups[]` form field, which gives the receipt each uploaded
+/// image belongs to, and returns the number of receipts.
+pub fn group_count(
+    groups: &[usize],
+    image_count: usize,
+    allowed: RangeInclusive<usize>,
+) -> Result<usize, ApiError> {
+    if groups.len() != image_count {
+        return Err(ApiError::InvalidParameter {
+            message: "groups[] must be given for every image".to_string(),
+            sensitive_message: Some(format!(
+                "{} groups for {} images",
+                groups.len(),
+                image_count
+            )),
+        });
+    }
+
+    let count = groups.iter().max().map_or(0, |g| g + 1);
+    if !allowed.contains(&count) {
+        return Err(ApiError::InvalidParameter {
+            message: format!(
+                "Between {} and {} receipts can be combined",
+                allowed.start(),
+                allowed.end()
+            ),
+            sensitive_message: Some(format!("{} receipts", count)),
+        });
+    }
+
+    if let Some(empty) = (0..count).find(|g| !groups.contains(g)) {
+        return Err(ApiError::InvalidParameter {
+            message: format!("Group {} has no images", empty),
+            sensitive_message: None,
+        });
+    }
+
+    Ok(count)
+}
+
+struct Section {
+    top: u32,
+    height: u32,
+    fingerprint: Vec<u8>,
+}
+
+impl Section {
+    fn matches(&self, other: &Section) -> bool {
+        let taller = self.height.max(other.height);
+        if self.height.abs_diff(other.height) as f32 > taller as f32 * MAX_HEIGHT_DIFFERENCE_RATIO {
+            return false;
+        }
+
+        let difference = self
+            .fingerprint
+            .iter()
+            .zip(&other.fingerprint)
+            .map(|(a, b)| a.abs_diff(*b) as u32)
+            .sum::<u32>();
+
+        difference <= MAX_SECTION_DIFFERENCE * self.fingerprint.len() as u32
+    }
+}
+
+/// Cuts the receipt at its row breaks.
+fn sections(receipt: &DynamicImage) -> Vec<Section> {
+    let mut cuts = vec![0];
+    cuts.extend(layout::row_breaks(receipt));
+    cuts.push(receipt.height());
+    cuts.dedup();
+
+    cuts.windows(2)
+        .map(|c| Section {
+            top: c[0],
+            height: c[1] - c[0],
+            fingerprint: receipt
+                .crop_imm(0, c[0], receipt.width(), c[1] - c[0])
+                .resize_exact(FINGERPRINT_WIDTH, FINGERPRINT_HEIGHT, FilterType::Triangle)
+                .to_luma8()
+                .into_raw(),
+        })
+        .collect()
+}
+
+/// Finds sections that appear in every receipt in the same order. Returns
+/// the index of each of them in every receipt.
+fn common_sections(sections: &[Vec<Section>]) -> Vec<Vec<usize>> {
+    let Some((first, rest)) = sections.split_first() else {
+        return Vec::new();
+    };
+
+    let mut anchors = (0..first.len()).map(|i| vec![i]).collect::<Vec<_>>();
+    for other in rest {
+        let candidates = anchors.iter().map(|a| &first[a[0]]).collect::<Vec<_>>();
+        anchors = longest_common_subsequence(&candidates, other)
+            .into_iter()
+            .map(|(a, b)| {
+                let mut anchor = anchors[a].clone();
+                anchor.push(b);
+                anchor
+            })
+            .collect();
+    }
+
+    anchors
+}
+
+/// Pairs of indices of the longest run of matching sections in both lists.
+fn longest_common_subsequence(a: &[&Section], b: &[Section]) -> Vec<(usize, usize)> {
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i].matches(&b[j]) {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].matches(&b[j]) {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    pairs
+}
@@ -0,0 +1,314 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone)]
+pub struct StitchingConfig {
+    pub workers: usize,
+    pub queue_depth: usize,
+    pub retry_after_seconds: u64,
+}
+
+impl StitchingConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let workers = env_or("STITCHING_WORKERS", default_workers())?;
+        anyhow::ensure!(workers > 0, "STITCHING_WORKERS must be greater than 0");
+
+        Ok(Self {
+            workers,
+            queue_depth: env_or("STITCHING_QUEUE_DEPTH", 16)?,
+            retry_after_seconds: env_or("STITCHING_RETRY_AFTER_SECONDS", 10)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    pub ttl: Duration,
+    pub sweep_interval: Duration,
+    /// Jobs kept at once, finished or not.
+    pub max_jobs: usize,
+}
+
+impl JobConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            ttl: Duration::from_secs(env_or("RECEIPT_JOB_TTL_SECONDS", 600)?),
+            sweep_interval: Duration::from_secs(env_or("RECEIPT_JOB_SWEEP_INTERVAL_SECONDS", 60)?),
+            max_jobs: env_or("RECEIPT_JOB_MAX_JOBS", 64)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareConfig {
+    /// How long a shared receipt stays up.
+    pub ttl: Duration,
+    pub sweep_interval: Duration,
+}
+
+impl ShareConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            ttl: Duration::from_secs(env_or("SHARE_TTL_SECONDS", 30 * 24 * 3600)?),
+            sweep_interval: Duration::from_secs(env_or("SHARE_SWEEP_INTERVAL_SECONDS", 3600)?),
+        })
+    }
+}
+
+/// Bounds of the cache of generated receipts. Receipts only go to disk when
+/// `directory` is set.
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    pub max_bytes: u64,
+    pub directory: Option<String>,
+    pub max_disk_bytes: u64,
+}
+
+impl CacheConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_entries: env_or("RESULT_CACHE_MAX_ENTRIES", 64)?,
+            max_bytes: env_or("RESULT_CACHE_MAX_BYTES", 256 * 1024 * 1024)?,
+            directory: env_optional("RESULT_CACHE_DIRECTORY")?,
+            max_disk_bytes: env_or("RESULT_CACHE_MAX_DISK_BYTES", 2 * 1024 * 1024 * 1024)?,
+        })
+    }
+}
+
+/// Where objects kept beyond a single request are stored, chosen by
+/// `STORAGE_BACKEND`.
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Local {
+        directory: String,
+    },
+    /// A bucket of S3 or a compatible service. `endpoint` is only set for
+    /// services other than AWS, such as MinIO, which usually also need
+    /// path-style URLs.
+    S3 {
+        bucket: String,
+        region: String,
+        endpoint: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+        path_style: bool,
+    },
+}
+
+impl StorageConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env_or("STORAGE_BACKEND", "local".to_string())?.as_str() {
+            "local" => Ok(Self::Local {
+                directory: env_or("STORAGE_DIRECTORY", "./storage".to_string())?,
+            }),
+            "s3" => Ok(Self::S3 {
+                bucket: env_optional("S3_BUCKET")?
+                    .context("S3_BUCKET must be set when STORAGE_BACKEND is s3")?,
+                region: env_or("S3_REGION", "us-east-1".to_string())?,
+                endpoint: env_optional("S3_ENDPOINT")?,
+                access_key_id: env_optional("S3_ACCESS_KEY_ID")?,
+                secret_access_key: env_optional("S3_SECRET_ACCESS_KEY")?,
+                path_style: env_or("S3_PATH_STYLE", false)?,
+            }),
+            backend => anyhow::bail!("Unsupported STORAGE_BACKEND {:?}", backend),
+        }
+    }
+}
+
+/// Uploads are written to a local directory for OpenCV to read, whichever
+/// storage backend is used. Every replica needs a directory of its own, as
+/// everything in it is removed at startup.
+#[derive(Debug, Clone)]
+pub struct TempDirConfig {
+    pub directory: String,
+    pub max_age: Duration,
+    pub sweep_interval: Duration,
+}
+
+impl TempDirConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            directory: env_or("TEMP_UPLOAD_DIRECTORY", "./images-temp".to_string())?,
+            max_age: Duration::from_secs(env_or("TEMP_UPLOAD_MAX_AGE_SECONDS", 3600)?),
+            sweep_interval: Duration::from_secs(env_or("TEMP_UPLOAD_SWEEP_INTERVAL_SECONDS", 600)?),
+        })
+    }
+}
+
+/// Allowed ranges of the stitching options clients may override.
+#[derive(Debug, Clone)]
+pub struct StitchOptionBounds {
+    pub match_threshold: RangeInclusive<u32>,
+    pub default_match_threshold: u32,
+    pub scaling_threshold_pixels: RangeInclusive<u32>,
+    pub default_scaling_threshold_pixels: u32,
+}
+
+impl StitchOptionBounds {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let bounds = Self {
+            match_threshold: env_or("MATCH_THRESHOLD_MIN", 1)?..=env_or("MATCH_THRESHOLD_MAX", 50)?,
+            default_match_threshold: env_or("MATCH_THRESHOLD_DEFAULT", 10)?,
+            scaling_threshold_pixels: env_or("SCALING_THRESHOLD_PIXELS_MIN", 100_000)?
+                ..=env_or("SCALING_THRESHOLD_PIXELS_MAX", 4_000_000)?,
+            default_scaling_threshold_pixels: env_or("SCALING_THRESHOLD_PIXELS_DEFAULT", 540_000)?,
+        };
+
+        anyhow::ensure!(
+            bounds
+                .match_threshold
+                .contains(&bounds.default_match_threshold),
+            "MATCH_THRESHOLD_DEFAULT is out of range"
+        );
+        anyhow::ensure!(
+            bounds
+                .scaling_threshold_pixels
+                .contains(&bounds.default_scaling_threshold_pixels),
+            "SCALING_THRESHOLD_PIXELS_DEFAULT is out of range"
+        );
+
+        Ok(bounds)
+    }
+
+    /// Validates an optional client supplied value against `range`.
+    pub fn check(
+        name: &str,
+        value: Option<u32>,
+        range: &RangeInclusive<u32>,
+        default: u32,
+    ) -> Result<u32, ApiError> {
+        match value {
+            Some(value) if !range.contains(&value) => Err(ApiError::InvalidParameter {
+                message: format!(
+                    "{} must be between {} and {}",
+                    name,
+                    range.start(),
+                    range.end()
+                ),
+                sensitive_message: Some(format!("Invalid {} {}", name, value)),
+            }),
+            Some(value) => Ok(value),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Per-request upload budgets, shared with the front end through `/config`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadLimits {
+    pub max_images: usize,
+    pub max_image_bytes: usize,
+    pub max_request_bytes: usize,
+    pub max_total_pixels: u64,
+    /// Screenshots the analysis and the debug overlay accept, as they stitch
+    /// every prefix of the upload.
+    pub max_analyzed_images: usize,
+    /// Screenshots automatic ordering accepts, as it compares every pair.
+    pub max_ordered_images: usize,
+}
+
+impl UploadLimits {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_images: env_or("MAX_IMAGES_PER_RECEIPT", 30)?,
+            max_image_bytes: env_or("MAX_IMAGE_BYTES", 20 * 1024 * 1024)?,
+            max_request_bytes: env_or("MAX_REQUEST_BYTES", 200 * 1024 * 1024)?,
+            max_total_pixels: env_or("MAX_TOTAL_PIXELS", 200_000_000)?,
+            max_analyzed_images: env_or("MAX_ANALYZED_IMAGES", 10)?,
+            max_ordered_images: env_or("MAX_ORDERED_IMAGES", 12)?,
+        })
+    }
+}
+
+/// Guards against decompression bombs when decoding uploaded screenshots.
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_decoded_bytes: u64,
+}
+
+impl DecodeLimits {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            max_width: env_or("MAX_IMAGE_WIDTH", 8192)?,
+            max_height: env_or("MAX_IMAGE_HEIGHT", 8192)?,
+            max_decoded_bytes: env_or("MAX_DECODED_IMAGE_BYTES", 256 * 1024 * 1024)?,
+        })
+    }
+}
+
+/// Limits on screen recordings uploaded instead of screenshots.
+#[derive(Debug, Clone)]
+pub struct VideoLimits {
+    pub max_video_bytes: usize,
+    pub max_duration: Duration,
+    /// Frames closer together than this are not looked at.
+    pub sample_interval: Duration,
+}
+
+impl VideoLimits {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let sample_interval =
+            Duration::from_millis(env_or("VIDEO_SAMPLE_INTERVAL_MILLISECONDS", 100)?);
+        anyhow::ensure!(
+            !sample_interval.is_zero(),
+            "VIDEO_SAMPLE_INTERVAL_MILLISECONDS must be greater than 0"
+        );
+
+        Ok(Self {
+            max_video_bytes: env_or("MAX_VIDEO_BYTES", 100 * 1024 * 1024)?,
+            max_duration: Duration::from_secs(env_or("MAX_VIDEO_SECONDS", 120)?),
+            sample_interval,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OcrConfig {
+    /// Directory holding the Tesseract `.traineddata` model.
+    pub model_directory: String,
+    pub language: String,
+}
+
+impl OcrConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(Self {
+            model_directory: env_or(
+                "OCR_MODEL_DIRECTORY",
+                "./server/assets/tessdata".to_string(),
+            )?,
+            language: env_or("OCR_LANGUAGE", "jpn".to_string())?,
+        })
+    }
+}
+
+fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+pub(crate) fn env_or<T>(key: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("Invalid value for {}: {:?}", key, value)),
+        Err(std::env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", key)),
+    }
+}
+
+/// Reads a variable that may be left unset or empty.
+fn env_optional(key: &str) -> anyhow::Result<Option<String>> {
+    Ok(Some(env_or(key, String::new())?).filter(|v| !v.is_empty()))
+}
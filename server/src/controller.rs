@@ -1 +1,4 @@
+pub(crate) mod config;
 pub(crate) mod receipt;
+pub(crate) mod receipt_job;
+pub(crate) mod share;
@@ -0,0 +1,8 @@
+use actix_web::{get, web, HttpResponse};
+
+use crate::config::UploadLimits;
+
+#[get("")]
+pub async fn show(upload_limits: web::Data<UploadLimits>) -> HttpResponse {
+    HttpResponse::Ok().json(upload_limits.get_ref())
+}
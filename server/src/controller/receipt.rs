@@ -1,138 +1,1192 @@
-use std::fs;
-use std::io::Cursor;
-
-use actix_multipart::form::{tempfile::TempFile, text::Text, MultipartForm};
-use actix_web::body::BoxBody;
-use actix_web::http::header::ContentType;
-use actix_web::{post, HttpRequest, HttpResponse, Responder, ResponseError};
-use image::ImageOutputFormat;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use actix_multipart::form::{bytes::Bytes, text::Text, MultipartForm};
+use actix_web::http::header::{
+    ContentDisposition, DispositionParam, DispositionType, ETag, EntityTag, Header, IfNoneMatch,
+    CONTENT_TYPE,
+};
+use actix_web::{post, web, HttpRequest, HttpResponse};
 use log::{error, info};
-use uma_details_utility::image::detail::{HeaderTrimMode, HorseGirlFullDetailImage, ImageConfig};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uma_details_utility::image::ImageMatrix;
 
+use crate::analysis::{self, ReceiptAnalysis};
+use crate::cache::ResultCache;
+use crate::caption::Decoration;
+use crate::comparison::{self, Comparison};
+use crate::config::{DecodeLimits, OcrConfig, StitchOptionBounds, UploadLimits, VideoLimits};
+use crate::debug_overlay;
+use crate::dedupe;
+use crate::detail::StitchOptions;
 use crate::error::ApiError;
-use crate::TEMP_UPLOAD_DIRECTORY;
+use crate::layout::ColumnLayout;
+use crate::ocr::{self, HorseGirlDetails};
+use crate::ordering;
+use crate::output::OutputFormat;
+use crate::paging::{self, PagePreset};
+use crate::redaction::Redaction;
+use crate::roster::{self, Roster};
+use crate::screen::{self, ScreenType};
+use crate::stitcher::{Admission, Stitcher};
+use crate::temp_dir::TempUploadStore;
+use crate::upload::UploadFormat;
+use crate::video::{self, VideoFormat};
+
+const REMOVED_IMAGE_INDICES_HEADER: &str = "X-Removed-Image-Indices";
 
 #[derive(Debug, MultipartForm)]
 pub struct CreateReceiptRequest {
     trim_margin: Option<Text<bool>>,
     trim_close_button: Option<Text<bool>>,
     trim_title: Option<Text<bool>>,
+    output_format: Option<Text<String>>,
+    quality: Option<Text<u8>>,
+    match_threshold: Option<Text<u32>>,
+    scaling_threshold_pixels: Option<Text<u32>>,
+    full_resolution: Option<Text<bool>>,
+    screen_type: Option<Text<String>>,
+    debug: Option<Text<bool>>,
+    columns: Option<Text<u32>>,
+    column_gutter: Option<Text<u32>>,
+    column_background: Option<Text<String>>,
+    pages: Option<Text<String>>,
+    redaction_style: Option<Text<String>>,
+    #[multipart(rename = "redact_regions[]")]
+    redact_regions: Vec<Text<String>>,
+    redact_personal_info: Option<Text<bool>>,
+    caption_handle: Option<Text<String>>,
+    caption_date: Option<Text<bool>>,
+    caption_note: Option<Text<String>>,
+    watermark: Option<Text<String>>,
+    auto_order: Option<Text<bool>>,
+    deduplicate: Option<Text<bool>>,
+    /// Index of the receipt each image belongs to, when comparing receipts.
+    #[multipart(rename = "groups[]")]
+    groups: Vec<Text<usize>>,
+    roster_title: Option<Text<String>>,
+    #[multipart(rename = "roster_labels[]")]
+    roster_labels: Vec<Text<String>>,
+    roster_columns: Option<Text<u32>>,
     #[multipart(rename = "images[]")]
-    images: Vec<TempFile>,
+    images: Vec<Bytes>,
 }
 
-pub struct ReceiptCreatedResponse {
-    image: image::DynamicImage,
+#[derive(Debug, MultipartForm)]
+pub struct CreateReceiptFromVideoRequest {
+    trim_margin: Option<Text<bool>>,
+    trim_close_button: Option<Text<bool>>,
+    trim_title: Option<Text<bool>>,
+    output_format: Option<Text<String>>,
+    quality: Option<Text<u8>>,
+    match_threshold: Option<Text<u32>>,
+    scaling_threshold_pixels: Option<Text<u32>>,
+    full_resolution: Option<Text<bool>>,
+    screen_type: Option<Text<String>>,
+    debug: Option<Text<bool>>,
+    columns: Option<Text<u32>>,
+    column_gutter: Option<Text<u32>>,
+    column_background: Option<Text<String>>,
+    pages: Option<Text<String>>,
+    redaction_style: Option<Text<String>>,
+    #[multipart(rename = "redact_regions[]")]
+    redact_regions: Vec<Text<String>>,
+    redact_personal_info: Option<Text<bool>>,
+    caption_handle: Option<Text<String>>,
+    caption_date: Option<Text<bool>>,
+    caption_note: Option<Text<String>>,
+    watermark: Option<Text<String>>,
+    video: Bytes,
 }
 
-impl Responder for ReceiptCreatedResponse {
-    type Body = BoxBody;
+/// A stitched receipt before it is encoded.
+#[derive(Clone)]
+pub struct GeneratedReceipt {
+    image: ReceiptImage,
+    /// Indices of uploads dropped as duplicates.
+    removed_indices: Vec<usize>,
+}
 
-    fn respond_to(self, _: &HttpRequest) -> HttpResponse<Self::Body> {
-        let mut bytes = Cursor::new(Vec::new());
-        self.image
-            .write_to(&mut bytes, ImageOutputFormat::Png)
-            .map(|_| {
-                info!("Responded ok");
+impl GeneratedReceipt {
+    /// Encodes the receipt, or a ZIP file of its pages, in `format`.
+    pub fn encode(&self, format: &OutputFormat) -> anyhow::Result<EncodedReceipt> {
+        let name = format!("receipt-{}", chrono::Local::now().format("%Y%m%d%H%M%S"));
 
-                HttpResponse::Ok()
-                    .insert_header(ContentType::png())
-                    .body(bytes.into_inner())
-            })
-            .unwrap_or_else(|_| {
-                ApiError::ImageGenerateError {
-                    message: "Failed to generate image".to_string(),
-                }
-                .error_response()
-            })
+        let (bytes, mime, file_name, dimensions) = match &self.image {
+            ReceiptImage::Single(image) => (
+                format.encode(image)?,
+                format.mime().to_string(),
+                format!("{}.{}", name, format.extension()),
+                Some((image.width(), image.height())),
+            ),
+            ReceiptImage::Pages(pages) => (
+                paging::archive(pages, format, &name)?,
+                "application/zip".to_string(),
+                format!("{}.zip", name),
+                None,
+            ),
+        };
+
+        Ok(EncodedReceipt {
+            bytes: bytes.into(),
+            mime,
+            file_name,
+            attachment: dimensions.is_none(),
+            dimensions,
+            removed_indices: self.removed_indices.clone(),
+        })
+    }
+
+    /// [`Self::encode`] for handlers, logging why encoding failed.
+    pub fn encode_for_response(&self, format: &OutputFormat) -> Result<EncodedReceipt, ApiError> {
+        self.encode(format).map_err(|e| {
+            error!("Failed to encode receipt: {:?}", e);
+            ApiError::ImageGenerateError {
+                message: "Failed to generate image".to_string(),
+            }
+        })
     }
 }
 
+#[derive(Clone)]
+pub enum ReceiptImage {
+    Single(image::DynamicImage),
+    /// Returned as a ZIP file of one image per page.
+    Pages(Vec<image::DynamicImage>),
+}
+
+/// A receipt encoded to be returned, as kept in the [`ResultCache`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodedReceipt {
+    #[serde(skip)]
+    pub bytes: web::Bytes,
+    mime: String,
+    file_name: String,
+    /// Pages are downloaded, a single image is displayed.
+    attachment: bool,
+    /// Width and height of a single image, `None` for pages.
+    #[serde(default)]
+    dimensions: Option<(u32, u32)>,
+    /// Indices of uploads dropped as duplicates.
+    removed_indices: Vec<usize>,
+}
+
+impl EncodedReceipt {
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.dimensions
+    }
+
+    pub fn response(&self, etag: Option<EntityTag>) -> HttpResponse {
+        info!("Responded ok");
+
+        let mut response = HttpResponse::Ok();
+        response
+            .insert_header((CONTENT_TYPE, self.mime.as_str()))
+            .insert_header(ContentDisposition {
+                disposition: if self.attachment {
+                    DispositionType::Attachment
+                } else {
+                    DispositionType::Inline
+                },
+                parameters: vec![DispositionParam::Filename(self.file_name.clone())],
+            });
+
+        if let Some(etag) = etag {
+            response.insert_header(ETag(etag));
+        }
+
+        if !self.removed_indices.is_empty() {
+            let removed_indices = self
+                .removed_indices
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            response.insert_header((REMOVED_IMAGE_INDICES_HEADER, removed_indices));
+        }
+
+        response.body(self.bytes.clone())
+    }
+}
+
+/// Hashes the uploads and the options into the key the receipt is cached
+/// by for `operation`.
+pub async fn cache_key(
+    receipt: PreparedReceipt,
+    operation: &'static str,
+    format: OutputFormat,
+) -> Result<(PreparedReceipt, String), ApiError> {
+    web::block(move || {
+        let key = receipt.cache_key(operation, &format);
+        (receipt, key)
+    })
+    .await
+    .map_err(|e| {
+        error!("Failed to hash uploads: {:?}", e);
+        ApiError::ImageGenerateError {
+            message: "Failed to generate image".to_string(),
+        }
+    })
+}
+
+/// Takes the receipt cached under `key` from the [`ResultCache`], or
+/// generates it with `generate`, encodes it and caches it. Only a cache miss
+/// takes a slot from the `stitcher`.
+pub async fn cached_or_generate(
+    stitcher: &Stitcher,
+    cache: web::Data<ResultCache>,
+    receipt: PreparedReceipt,
+    key: String,
+    format: OutputFormat,
+    generate: fn(PreparedReceipt) -> Result<GeneratedReceipt, ApiError>,
+) -> Result<Arc<EncodedReceipt>, ApiError> {
+    let (lookup, lookup_key) = (cache.clone(), key.clone());
+    if let Ok(Some(cached)) = web::block(move || lookup.get(&lookup_key)).await {
+        info!("Receipt {} served from cache", key);
+        return Ok(cached);
+    }
+
+    let encoded = stitcher
+        .run(move || generate(receipt)?.encode_for_response(&format))
+        .await?;
+    let encoded = Arc::new(encoded);
+
+    let cached = encoded.clone();
+    if let Err(e) = web::block(move || cache.put(&key, cached)).await {
+        error!("Failed to cache receipt: {:?}", e);
+    }
+
+    Ok(encoded)
+}
+
+/// Serves the receipt through [`cached_or_generate`]. Requests whose
+/// `If-None-Match` names the receipt are answered with 304 Not Modified
+/// without generating anything.
+async fn generate_cached(
+    http_request: &HttpRequest,
+    stitcher: &Stitcher,
+    cache: web::Data<ResultCache>,
+    receipt: PreparedReceipt,
+    operation: &'static str,
+    generate: fn(PreparedReceipt) -> Result<GeneratedReceipt, ApiError>,
+) -> Result<HttpResponse, ApiError> {
+    let format = receipt.output_format(http_request);
+    let (receipt, key) = cache_key(receipt, operation, format).await?;
+    let etag = EntityTag::new_strong(key.clone());
+
+    // RFC 9110 answers a failed If-None-Match on a POST with 412. Clients
+    // only send it here to revalidate a receipt they already have, which
+    // these POSTs return just like a GET would, so they get the 304 a GET
+    // would give them instead.
+    let is_not_modified = match IfNoneMatch::parse(http_request) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&etag)),
+        Err(_) => false,
+    };
+    if is_not_modified {
+        info!("Receipt {} not modified", key);
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish());
+    }
+
+    let encoded = cached_or_generate(stitcher, cache, receipt, key, format, generate).await?;
+
+    Ok(encoded.response(Some(etag)))
+}
+
 #[post("")]
 pub async fn insert(
     MultipartForm(request): MultipartForm<CreateReceiptRequest>,
-) -> Result<ReceiptCreatedResponse, ApiError> {
-    let request_id = uuid::Uuid::new_v4();
+    http_request: HttpRequest,
+    stitcher: web::Data<Stitcher>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+    cache: web::Data<ResultCache>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+
+    generate_cached(
+        &http_request,
+        &stitcher,
+        cache,
+        receipt,
+        "receipt",
+        PreparedReceipt::generate,
+    )
+    .await
+}
 
-    let trim_margin = request.trim_margin.map_or(Default::default(), |i| i.0);
-    let trim_close_button = request.trim_close_button.map_or(Default::default(), |i| i.0);
-    let trim_title = request.trim_title.map_or(Default::default(), |i| i.0);
+#[post("/video")]
+pub async fn insert_video(
+    admission: Admission,
+    MultipartForm(request): MultipartForm<CreateReceiptFromVideoRequest>,
+    http_request: HttpRequest,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    video_limits: web::Data<VideoLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+    temp_uploads: web::Data<TempUploadStore>,
+) -> Result<HttpResponse, ApiError> {
+    let recording = PreparedRecording::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &video_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    let format = recording.output_format(&http_request);
 
-    let dir_path = format!("{}/{}", TEMP_UPLOAD_DIRECTORY, request_id);
-    let lock_path = format!("{}/.lock", dir_path);
+    let receipt = admission
+        .run(move || {
+            recording
+                .generate(&temp_uploads)?
+                .encode_for_response(&format)
+        })
+        .await?;
 
-    let lock_file = fs::read(lock_path.clone());
-    if lock_file.is_ok() {
-        error!("Directory {} is locked!", dir_path);
-        return Err(ApiError::ImageUploadError {
-            message: "Failed to upload image".to_string(),
+    Ok(receipt.response(None))
+}
+
+#[post("/analyze")]
+pub async fn analyze(
+    admission: Admission,
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    require_horse_girl_detail(&receipt.options, "analyze")?;
+    if receipt.images.len() > upload_limits.max_analyzed_images {
+        return Err(ApiError::TooManyImages {
+            limit: upload_limits.max_analyzed_images,
+        });
+    }
+
+    let analysis = admission.run(move || receipt.analyze()).await?;
+
+    Ok(HttpResponse::Ok().json(analysis))
+}
+
+#[post("/ocr")]
+pub async fn recognize(
+    admission: Admission,
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    require_horse_girl_detail(&receipt.options, "ocr")?;
+
+    let details = admission
+        .run(move || receipt.recognize(&ocr_config))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(details))
+}
+
+#[post("/compare")]
+pub async fn compare(
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    http_request: HttpRequest,
+    stitcher: web::Data<Stitcher>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+    cache: web::Data<ResultCache>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+
+    generate_cached(
+        &http_request,
+        &stitcher,
+        cache,
+        receipt,
+        "compare",
+        PreparedReceipt::compare,
+    )
+    .await
+}
+
+#[post("/roster")]
+pub async fn insert_roster(
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    http_request: HttpRequest,
+    stitcher: web::Data<Stitcher>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+    cache: web::Data<ResultCache>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+
+    generate_cached(
+        &http_request,
+        &stitcher,
+        cache,
+        receipt,
+        "roster",
+        PreparedReceipt::generate_roster,
+    )
+    .await
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReceiptOrderResponse {
+    order: Vec<usize>,
+}
+
+#[post("/order")]
+pub async fn order(
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    stitcher: web::Data<Stitcher>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    require_horse_girl_detail(&receipt.options, "order")?;
+    if receipt.images.len() > upload_limits.max_ordered_images {
+        return Err(ApiError::TooManyImages {
+            limit: upload_limits.max_ordered_images,
         });
     }
 
-    fs::create_dir_all(dir_path.clone())?;
-    fs::write(lock_path, "")?;
+    let order = stitcher
+        .run(move || {
+            let images = receipt.decode()?;
+            ordering::order(&images, &receipt.options)
+        })
+        .await?;
 
-    let mut index = 1;
-    for image in request.images {
-        let mime = image
-            .content_type
-            .clone()
-            .ok_or(ApiError::InvalidParameter {
-                message: "Invalid image".to_string(),
-                sensitive_message: Some("Cannot identifying file content type".to_string()),
-            })?;
+    Ok(HttpResponse::Ok().json(ReceiptOrderResponse { order }))
+}
 
-        if mime != mime::IMAGE_PNG {
+/// Uploaded screenshots waiting to be stitched into a receipt.
+pub struct PreparedReceipt {
+    images: Vec<UploadedImage>,
+    decode_limits: DecodeLimits,
+    max_total_pixels: u64,
+    options: StitchOptions,
+    output_format: Option<OutputFormat>,
+    render_options: RenderOptions,
+    comparison: Comparison,
+    roster: Roster,
+    groups: Vec<usize>,
+    auto_order: bool,
+    deduplicate: bool,
+}
+
+impl PreparedReceipt {
+    pub fn upload(
+        request: CreateReceiptRequest,
+        upload_limits: &UploadLimits,
+        decode_limits: &DecodeLimits,
+        bounds: &StitchOptionBounds,
+        ocr_config: &OcrConfig,
+    ) -> Result<Self, ApiError> {
+        if request.images.len() > upload_limits.max_images {
+            return Err(ApiError::TooManyImages {
+                limit: upload_limits.max_images,
+            });
+        }
+
+        if let Some(index) = request
+            .images
+            .iter()
+            .position(|i| i.data.len() > upload_limits.max_image_bytes)
+        {
+            return Err(ApiError::ImageTooLarge {
+                index,
+                limit: upload_limits.max_image_bytes,
+            });
+        }
+
+        if request.images.iter().map(|i| i.data.len()).sum::<usize>()
+            > upload_limits.max_request_bytes
+        {
+            return Err(ApiError::RequestTooLarge {
+                limit: upload_limits.max_request_bytes,
+            });
+        }
+
+        let options = stitch_options(
+            StitchFields {
+                trim_margin: request.trim_margin,
+                trim_close_button: request.trim_close_button,
+                trim_title: request.trim_title,
+                full_resolution: request.full_resolution,
+                match_threshold: request.match_threshold,
+                scaling_threshold_pixels: request.scaling_threshold_pixels,
+                screen_type: request.screen_type,
+            },
+            bounds,
+        )?;
+        let output_format = request
+            .output_format
+            .map(|f| OutputFormat::from_form(f.as_str(), request.quality.map(|q| q.0)))
+            .transpose()?;
+        let comparison = Comparison::from_form(
+            request.column_gutter.as_ref().map(|i| i.0),
+            request.column_background.as_ref().map(|b| b.as_str()),
+        )?;
+        let roster = Roster::from_form(
+            request.roster_title.map(|i| i.0),
+            request.roster_labels.into_iter().map(|i| i.0).collect(),
+            request.roster_columns.map(|i| i.0),
+            request.column_gutter.as_ref().map(|i| i.0),
+            request.column_background.as_ref().map(|b| b.as_str()),
+        )?;
+        let render_options = RenderOptions::from_form(
+            RenderFields {
+                debug: request.debug,
+                columns: request.columns,
+                column_gutter: request.column_gutter,
+                column_background: request.column_background,
+                pages: request.pages,
+                redaction_style: request.redaction_style,
+                redact_regions: request.redact_regions,
+                redact_personal_info: request.redact_personal_info,
+                caption_handle: request.caption_handle,
+                caption_date: request.caption_date,
+                caption_note: request.caption_note,
+                watermark: request.watermark,
+            },
+            ocr_config,
+        )?;
+        let groups = request.groups.into_iter().map(|g| g.0).collect();
+        let auto_order = request.auto_order.map_or(Default::default(), |i| i.0);
+        if render_options.debug {
+            require_horse_girl_detail(&options, "debug")?;
+            if request.images.len() > upload_limits.max_analyzed_images {
+                return Err(ApiError::TooManyImages {
+                    limit: upload_limits.max_analyzed_images,
+                });
+            }
+        }
+        if auto_order {
+            require_horse_girl_detail(&options, "auto_order")?;
+            if request.images.len() > upload_limits.max_ordered_images {
+                return Err(ApiError::TooManyImages {
+                    limit: upload_limits.max_ordered_images,
+                });
+            }
+        }
+        let deduplicate = request.deduplicate.map_or(Default::default(), |i| i.0);
+
+        let mut images = Vec::with_capacity(request.images.len());
+        let mut rejected = Vec::new();
+        for (index, image) in request.images.into_iter().enumerate() {
+            let file_name = image
+                .file_name
+                .clone()
+                .unwrap_or_else(|| "unnamed".to_string());
+
+            match UploadFormat::sniff(&image.data) {
+                Some(format) => images.push(UploadedImage {
+                    index,
+                    file_name,
+                    image,
+                    format,
+                }),
+                None => rejected.push(format!("images[{}] ({})", index, file_name)),
+            }
+        }
+
+        if !rejected.is_empty() {
             return Err(ApiError::InvalidParameter {
-                message: "Unsupported file type".to_string(),
-                sensitive_message: Some(format!("File type {} is not supported", mime)),
+                message: format!("Unsupported file type: {}", rejected.join(", ")),
+                sensitive_message: None,
             });
         }
 
-        let file_path = format!("{}/{}.png", dir_path, index);
+        Ok(Self {
+            images,
+            decode_limits: decode_limits.clone(),
+            max_total_pixels: upload_limits.max_total_pixels,
+            options,
+            output_format,
+            render_options,
+            comparison,
+            roster,
+            groups,
+            auto_order,
+            deduplicate,
+        })
+    }
 
-        fs::create_dir_all(dir_path.clone())?;
+    /// The format requested by the `output_format` form field, falling back
+    /// to the request's `Accept` header and then to PNG.
+    pub fn output_format(&self, request: &HttpRequest) -> OutputFormat {
+        self.output_format
+            .or_else(|| OutputFormat::negotiate(request))
+            .unwrap_or_default()
+    }
+
+    /// Identifies the receipt `operation` generates from the uploads in
+    /// `format`. Covers the uploads in order and every option, including the
+    /// date of the caption, and changes with the server version.
+    fn cache_key(&self, operation: &str, format: &OutputFormat) -> String {
+        let options = format!(
+            "{} {} {:?} {:?} {:?} {:?} {:?} {:?} {} {}",
+            env!("CARGO_PKG_VERSION"),
+            operation,
+            format,
+            self.options,
+            self.render_options,
+            self.comparison,
+            self.roster,
+            self.groups,
+            self.auto_order,
+            self.deduplicate,
+        );
 
-        image
-            .file
-            .persist(file_path.as_str())
-            .map_err(|_| ApiError::ImageUploadError {
-                message: "Failed to upload image".to_string(),
-            })?;
-        info!("Image uploaded to {:?}", file_path);
+        // File names only show up in the debug overlay, so renaming the
+        // same screenshots otherwise still hits the cache.
+        let mut hasher = Sha256::new();
+        let parts = self.images.iter().flat_map(|i| {
+            let file_name = if self.render_options.debug {
+                i.file_name.as_bytes()
+            } else {
+                &[]
+            };
+            [file_name, &i.image.data[..]]
+        });
+        for part in [options.as_bytes()].into_iter().chain(parts) {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
 
-        index += 1;
+        format!("{:x}", hasher.finalize())
     }
 
-    let header_trim_mode = if trim_margin {
-        Some(if trim_title {
-            HeaderTrimMode::TrimTitleBar
+    /// Stitches the uploaded screenshots.
+    pub fn generate(self) -> Result<GeneratedReceipt, ApiError> {
+        let images = self.decode()?;
+        let indices = (0..images.len()).collect();
+        let (images, file_names, removed_indices) = self.arrange(images, indices)?;
+
+        Ok(GeneratedReceipt {
+            image: self
+                .render_options
+                .render(&images, &file_names, &self.options)?,
+            removed_indices,
+        })
+    }
+
+    /// Stitches the uploaded screenshots and reads the details of the
+    /// horse girl off the result.
+    pub fn recognize(self, config: &OcrConfig) -> Result<HorseGirlDetails, ApiError> {
+        let images = self.decode()?;
+        let indices = (0..images.len()).collect();
+        let (images, ..) = self.arrange(images, indices)?;
+        let image = screen::stitch(&images, &self.options)?;
+
+        ocr::recognize(&image, config)
+    }
+
+    /// Stitches each group of screenshots given by `groups[]` into its own
+    /// receipt and lays them out side by side.
+    pub fn compare(self) -> Result<GeneratedReceipt, ApiError> {
+        let (receipts, removed_indices) = self.stitch_groups(2..=comparison::MAX_RECEIPTS)?;
+
+        Ok(GeneratedReceipt {
+            image: self
+                .render_options
+                .finish(self.comparison.compose(&receipts))?,
+            removed_indices,
+        })
+    }
+
+    /// Stitches each group of screenshots given by `groups[]` into the
+    /// receipt of one runner and lays them out as a team sheet.
+    pub fn generate_roster(self) -> Result<GeneratedReceipt, ApiError> {
+        let (receipts, removed_indices) = self.stitch_groups(1..=roster::MAX_RUNNERS)?;
+
+        Ok(GeneratedReceipt {
+            image: self.render_options.finish(self.roster.compose(&receipts))?,
+            removed_indices,
+        })
+    }
+
+    /// Stitches each group of screenshots given by `groups[]` on its own.
+    /// Returns the receipts and the indices of the uploads dropped as
+    /// duplicates.
+    fn stitch_groups(
+        &self,
+        allowed: RangeInclusive<usize>,
+    ) -> Result<(Vec<image::DynamicImage>, Vec<usize>), ApiError> {
+        // Without `groups[]`, every image belongs to one receipt.
+        let image_groups = if self.groups.is_empty() {
+            vec![0; self.images.len()]
         } else {
-            HeaderTrimMode::TrimMarginOnly
+            self.groups.clone()
+        };
+        let count = comparison::group_count(&image_groups, self.images.len(), allowed)?;
+
+        let mut groups = vec![(Vec::new(), Vec::new()); count];
+        for (index, (image, &group)) in self.decode()?.into_iter().zip(&image_groups).enumerate() {
+            groups[group].0.push(image);
+            groups[group].1.push(index);
+        }
+
+        let mut receipts = Vec::with_capacity(count);
+        let mut removed_indices = Vec::new();
+        for (images, indices) in groups {
+            let (images, file_names, removed) = self.arrange(images, indices)?;
+            receipts.push(
+                self.render_options
+                    .stitch(&images, &file_names, &self.options)?,
+            );
+            removed_indices.extend(removed);
+        }
+        removed_indices.sort_unstable();
+
+        Ok((receipts, removed_indices))
+    }
+
+    /// Drops duplicates from decoded uploads and reorders them if requested.
+    /// `indices` are the positions of the images in the upload. Returns the
+    /// images, their file names and the indices of the uploads dropped as
+    /// duplicates.
+    fn arrange(
+        &self,
+        mut images: Vec<image::DynamicImage>,
+        mut indices: Vec<usize>,
+    ) -> Result<(Vec<image::DynamicImage>, Vec<String>, Vec<usize>), ApiError> {
+        let mut removed_indices = Vec::new();
+        if self.deduplicate {
+            let (kept, removed) = dedupe::deduplicate(images);
+            removed_indices = removed.iter().map(|&i| indices[i]).collect();
+            info!("Duplicated screenshots removed: {:?}", removed_indices);
+            indices = indices
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| !removed.contains(i))
+                .map(|(_, index)| index)
+                .collect();
+            images = kept;
+        }
+
+        if self.auto_order {
+            let order = ordering::order(&images, &self.options)?;
+            info!("Screenshots reordered to {:?}", order);
+            images = reorder(images, &order);
+            indices = reorder(indices, &order);
+        }
+
+        let file_names = indices
+            .iter()
+            .map(|&i| self.images[i].file_name.clone())
+            .collect();
+
+        Ok((images, file_names, removed_indices))
+    }
+
+    /// Reports how each screenshot would be stitched without producing an
+    /// image.
+    pub fn analyze(self) -> Result<ReceiptAnalysis, ApiError> {
+        let images = self.decode()?;
+
+        Ok(analysis::analyze(&images, &self.file_names(), &self.options).0)
+    }
+
+    fn file_names(&self) -> Vec<String> {
+        self.images.iter().map(|i| i.file_name.clone()).collect()
+    }
+
+    /// Fully decodes every upload in memory.
+    fn decode(&self) -> Result<Vec<image::DynamicImage>, ApiError> {
+        let mut total_pixels = 0;
+        let mut decoded = Vec::with_capacity(self.images.len());
+
+        for uploaded in &self.images {
+            let image = uploaded
+                .format
+                .decode(&uploaded.image.data, &self.decode_limits)
+                .map_err(|e| ApiError::InvalidParameter {
+                    message: format!(
+                        "images[{}] ({}): invalid or too large image",
+                        uploaded.index, uploaded.file_name
+                    ),
+                    sensitive_message: Some(format!("{:?}", e)),
+                })?;
+
+            total_pixels += image.width() as u64 * image.height() as u64;
+            if total_pixels > self.max_total_pixels {
+                return Err(ApiError::PixelBudgetExceeded {
+                    limit: self.max_total_pixels,
+                });
+            }
+
+            decoded.push(image);
+        }
+
+        Ok(decoded)
+    }
+}
+
+/// An uploaded screen recording waiting to be stitched into a receipt.
+pub struct PreparedRecording {
+    video: Bytes,
+    format: VideoFormat,
+    video_limits: VideoLimits,
+    decode_limits: DecodeLimits,
+    max_images: usize,
+    max_total_pixels: u64,
+    options: StitchOptions,
+    output_format: Option<OutputFormat>,
+    render_options: RenderOptions,
+}
+
+impl PreparedRecording {
+    pub fn upload(
+        request: CreateReceiptFromVideoRequest,
+        upload_limits: &UploadLimits,
+        decode_limits: &DecodeLimits,
+        video_limits: &VideoLimits,
+        bounds: &StitchOptionBounds,
+        ocr_config: &OcrConfig,
+    ) -> Result<Self, ApiError> {
+        if request.video.data.len() > video_limits.max_video_bytes {
+            return Err(ApiError::VideoTooLarge {
+                limit: video_limits.max_video_bytes,
+            });
+        }
+
+        let options = stitch_options(
+            StitchFields {
+                trim_margin: request.trim_margin,
+                trim_close_button: request.trim_close_button,
+                trim_title: request.trim_title,
+                full_resolution: request.full_resolution,
+                match_threshold: request.match_threshold,
+                scaling_threshold_pixels: request.scaling_threshold_pixels,
+                screen_type: request.screen_type,
+            },
+            bounds,
+        )?;
+        let output_format = request
+            .output_format
+            .map(|f| OutputFormat::from_form(f.as_str(), request.quality.map(|q| q.0)))
+            .transpose()?;
+        let render_options = RenderOptions::from_form(
+            RenderFields {
+                debug: request.debug,
+                columns: request.columns,
+                column_gutter: request.column_gutter,
+                column_background: request.column_background,
+                pages: request.pages,
+                redaction_style: request.redaction_style,
+                redact_regions: request.redact_regions,
+                redact_personal_info: request.redact_personal_info,
+                caption_handle: request.caption_handle,
+                caption_date: request.caption_date,
+                caption_note: request.caption_note,
+                watermark: request.watermark,
+            },
+            ocr_config,
+        )?;
+
+        if render_options.debug {
+            require_horse_girl_detail(&options, "debug")?;
+        }
+
+        let format = VideoFormat::sniff(&request.video.data).ok_or(ApiError::InvalidParameter {
+            message: "Unsupported file type: video".to_string(),
+            sensitive_message: None,
+        })?;
+
+        Ok(Self {
+            video: request.video,
+            format,
+            video_limits: video_limits.clone(),
+            decode_limits: decode_limits.clone(),
+            // The debug overlay stitches every prefix of the frames.
+            max_images: if render_options.debug {
+                upload_limits.max_analyzed_images
+            } else {
+                upload_limits.max_images
+            },
+            max_total_pixels: upload_limits.max_total_pixels,
+            options,
+            output_format,
+            render_options,
         })
-    } else {
-        None
-    };
+    }
 
-    let detail = HorseGirlFullDetailImage::from_path(
-        dir_path.as_str(),
-        10,
-        ImageConfig {
-            do_merge_close_button: !trim_close_button,
-            header_trim_mode,
-            scaling_threshold_pixels: Some(540000)
-        },
+    /// The format requested by the `output_format` form field, falling back
+    /// to the request's `Accept` header and then to PNG.
+    pub fn output_format(&self, request: &HttpRequest) -> OutputFormat {
+        self.output_format
+            .or_else(|| OutputFormat::negotiate(request))
+            .unwrap_or_default()
+    }
+
+    /// Picks frames from the recording and stitches them.
+    pub fn generate(self, temp_uploads: &TempUploadStore) -> Result<GeneratedReceipt, ApiError> {
+        let frames = video::extract_frames(
+            temp_uploads,
+            &self.video.data,
+            self.format,
+            &self.options,
+            &self.video_limits,
+            &self.decode_limits,
+            self.max_images,
+            self.max_total_pixels,
+        )?;
+
+        let file_names = frames
+            .iter()
+            .map(|f| format!("{:.1}s", f.position.as_secs_f64()))
+            .collect::<Vec<_>>();
+        let images = frames.into_iter().map(|f| f.image).collect::<Vec<_>>();
+
+        Ok(GeneratedReceipt {
+            image: self
+                .render_options
+                .render(&images, &file_names, &self.options)?,
+            removed_indices: Vec::new(),
+        })
+    }
+}
+
+/// Form fields deciding how screenshots are stitched, shared by every upload
+/// form.
+struct StitchFields {
+    trim_margin: Option<Text<bool>>,
+    trim_close_button: Option<Text<bool>>,
+    trim_title: Option<Text<bool>>,
+    full_resolution: Option<Text<bool>>,
+    match_threshold: Option<Text<u32>>,
+    scaling_threshold_pixels: Option<Text<u32>>,
+    screen_type: Option<Text<String>>,
+}
+
+/// Parses the stitching options shared by every upload form.
+fn stitch_options(
+    fields: StitchFields,
+    bounds: &StitchOptionBounds,
+) -> Result<StitchOptions, ApiError> {
+    let trim_margin = fields.trim_margin.map_or(Default::default(), |i| i.0);
+    let trim_close_button = fields.trim_close_button.map_or(Default::default(), |i| i.0);
+    let trim_title = fields.trim_title.map_or(Default::default(), |i| i.0);
+    let full_resolution = fields.full_resolution.map_or(Default::default(), |i| i.0);
+    let screen_type = fields
+        .screen_type
+        .map(|s| ScreenType::from_form(s.as_str()))
+        .transpose()?
+        .unwrap_or_default();
+    let match_threshold = StitchOptionBounds::check(
+        "match_threshold",
+        fields.match_threshold.map(|i| i.0),
+        &bounds.match_threshold,
+        bounds.default_match_threshold,
+    )?;
+    let scaling_threshold_pixels = StitchOptionBounds::check(
+        "scaling_threshold_pixels",
+        fields.scaling_threshold_pixels.map(|i| i.0),
+        &bounds.scaling_threshold_pixels,
+        bounds.default_scaling_threshold_pixels,
     )?;
 
-    for file in fs::read_dir(dir_path.clone())? {
-        fs::remove_file(file?.path())?;
+    Ok(StitchOptions {
+        trim_margin,
+        trim_close_button,
+        trim_title,
+        match_threshold,
+        scaling_threshold_pixels: if full_resolution {
+            None
+        } else {
+            Some(scaling_threshold_pixels)
+        },
+        screen_type,
+    })
+}
+
+/// The debug overlay, analysis, automatic ordering and OCR only understand the
+/// horse girl detail screen.
+fn require_horse_girl_detail(options: &StitchOptions, feature: &str) -> Result<(), ApiError> {
+    if options.screen_type.is_horse_girl_detail() {
+        return Ok(());
     }
-    fs::remove_dir(dir_path)?;
 
-    let response = detail
-        .convert_to_image()
-        .map(|image| ReceiptCreatedResponse { image })?;
+    Err(ApiError::InvalidParameter {
+        message: format!(
+            "{} is only supported for the horse girl detail screen",
+            feature
+        ),
+        sensitive_message: Some(format!("Screen type {:?}", options.screen_type)),
+    })
+}
+
+/// Form fields deciding how the stitched receipt is returned, shared by
+/// every upload form.
+struct RenderFields {
+    debug: Option<Text<bool>>,
+    columns: Option<Text<u32>>,
+    column_gutter: Option<Text<u32>>,
+    column_background: Option<Text<String>>,
+    pages: Option<Text<String>>,
+    redaction_style: Option<Text<String>>,
+    redact_regions: Vec<Text<String>>,
+    redact_personal_info: Option<Text<bool>>,
+    caption_handle: Option<Text<String>>,
+    caption_date: Option<Text<bool>>,
+    caption_note: Option<Text<String>>,
+    watermark: Option<Text<String>>,
+}
+
+/// How the stitched receipt is turned into the returned image.
+#[derive(Debug, Clone)]
+struct RenderOptions {
+    debug: bool,
+    redaction: Option<Redaction>,
+    layout: Option<ColumnLayout>,
+    decoration: Option<Decoration>,
+    pages: Option<PagePreset>,
+}
+
+impl RenderOptions {
+    fn from_form(fields: RenderFields, ocr_config: &OcrConfig) -> Result<Self, ApiError> {
+        Ok(Self {
+            debug: fields.debug.map_or(Default::default(), |i| i.0),
+            redaction: Redaction::from_form(
+                fields.redaction_style.as_ref().map(|s| s.as_str()),
+                &fields
+                    .redact_regions
+                    .into_iter()
+                    .map(|r| r.0)
+                    .collect::<Vec<_>>(),
+                fields
+                    .redact_personal_info
+                    .map_or(Default::default(), |i| i.0),
+                ocr_config,
+            )?,
+            layout: ColumnLayout::from_form(
+                fields.columns.map(|i| i.0),
+                fields.column_gutter.map(|i| i.0),
+                fields.column_background.as_ref().map(|b| b.as_str()),
+            )?,
+            decoration: Decoration::from_form(
+                fields.caption_handle.map(|i| i.0),
+                fields.caption_date.map_or(Default::default(), |i| i.0),
+                fields.caption_note.map(|i| i.0),
+                fields.watermark.map(|i| i.0),
+            )?,
+            pages: fields
+                .pages
+                .map(|p| PagePreset::from_form(p.as_str()))
+                .transpose()?,
+        })
+    }
+
+    /// Stitches decoded screenshots and finishes the result as requested.
+    fn render(
+        &self,
+        images: &[image::DynamicImage],
+        file_names: &[String],
+        options: &StitchOptions,
+    ) -> Result<ReceiptImage, ApiError> {
+        self.finish(self.stitch(images, file_names, options)?)
+    }
+
+    /// Stitches decoded screenshots, or draws where they were stitched if
+    /// `debug` is set.
+    fn stitch(
+        &self,
+        images: &[image::DynamicImage],
+        file_names: &[String],
+        options: &StitchOptions,
+    ) -> Result<image::DynamicImage, ApiError> {
+        if self.debug {
+            debug_overlay::render(images, file_names, options)
+        } else {
+            screen::stitch(images, options)
+        }
+    }
+
+    /// Redacts, splits into columns, captions and splits into pages as
+    /// requested.
+    fn finish(&self, mut image: image::DynamicImage) -> Result<ReceiptImage, ApiError> {
+        if let Some(redaction) = &self.redaction {
+            redaction.apply(&mut image)?;
+        }
+
+        let image = match &self.layout {
+            Some(layout) => layout.apply(&image),
+            None => image,
+        };
+
+        let image = match &self.decoration {
+            Some(decoration) => decoration.apply(&image),
+            None => image,
+        };
+
+        Ok(match &self.pages {
+            Some(pages) => ReceiptImage::Pages(pages.split(&image)?),
+            None => ReceiptImage::Single(image),
+        })
+    }
+}
+
+/// An upload held in memory whose content has not been decoded yet.
+struct UploadedImage {
+    index: usize,
+    file_name: String,
+    image: Bytes,
+    format: UploadFormat,
+}
 
-    Ok(response)
+fn reorder<T>(items: Vec<T>, order: &[usize]) -> Vec<T> {
+    let mut items = items.into_iter().map(Some).collect::<Vec<_>>();
+    order.iter().filter_map(|&i| items[i].take()).collect()
 }
@@ -0,0 +1,111 @@
+use actix_multipart::form::MultipartForm;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use log::{error, info};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::{DecodeLimits, OcrConfig, StitchOptionBounds, UploadLimits};
+use crate::controller::receipt::{CreateReceiptRequest, PreparedReceipt};
+use crate::error::ApiError;
+use crate::job::{JobState, JobStatus, JobStore};
+use crate::stitcher::Admission;
+
+#[derive(Debug, Serialize)]
+pub struct ReceiptJobResponse<'a> {
+    id: String,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ApiError>,
+}
+
+#[post("/jobs")]
+pub async fn insert(
+    admission: Admission,
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    http_request: HttpRequest,
+    jobs: web::Data<JobStore>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    let format = receipt.output_format(&http_request);
+
+    let id = jobs.create()?;
+    info!("Receipt job {} queued", id);
+
+    let job_store = jobs.clone();
+    actix_web::rt::spawn(async move {
+        let store = job_store.clone();
+        let result = admission
+            .run(move || {
+                store.update(&id, JobState::Processing);
+                receipt.generate()?.encode_for_response(&format)
+            })
+            .await;
+
+        match result {
+            Ok(receipt) => {
+                info!("Receipt job {} done", id);
+                job_store.update(&id, JobState::Done(receipt));
+            }
+            Err(e) => {
+                error!("Receipt job {} failed: {:?}", id, e);
+                job_store.update(&id, JobState::Failed(e));
+            }
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(ReceiptJobResponse {
+        id: id.to_string(),
+        status: JobStatus::Queued,
+        error: None,
+    }))
+}
+
+#[get("/jobs/{id}")]
+pub async fn show(
+    path: web::Path<String>,
+    jobs: web::Data<JobStore>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_job_id(path.into_inner())?;
+
+    jobs.with(&id, |state| {
+        HttpResponse::Ok().json(ReceiptJobResponse {
+            id: id.to_string(),
+            status: state.status(),
+            error: match state {
+                JobState::Failed(e) => Some(e),
+                _ => None,
+            },
+        })
+    })
+    .ok_or(ApiError::JobNotFound { id: id.to_string() })
+}
+
+#[get("/jobs/{id}/image")]
+pub async fn show_image(
+    path: web::Path<String>,
+    jobs: web::Data<JobStore>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_job_id(path.into_inner())?;
+
+    jobs.with(&id, |state| match state {
+        JobState::Done(receipt) => Ok(receipt.response(None)),
+        state => Err(ApiError::JobNotFinished {
+            status: state.status(),
+        }),
+    })
+    .ok_or(ApiError::JobNotFound { id: id.to_string() })?
+}
+
+fn parse_job_id(id: String) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(id.as_str()).map_err(|_| ApiError::JobNotFound { id })
+}
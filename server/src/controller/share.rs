@@ -0,0 +1,245 @@
+use actix_multipart::form::MultipartForm;
+use actix_web::http::header::{self, CacheControl, CacheDirective, ContentType};
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use chrono::TimeZone;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::cache::ResultCache;
+use crate::config::{DecodeLimits, OcrConfig, ShareConfig, StitchOptionBounds, UploadLimits};
+use crate::controller::receipt::{self, CreateReceiptRequest, PreparedReceipt};
+use crate::error::ApiError;
+use crate::share::{self, ShareStore, SharedReceipt};
+use crate::stitcher::Stitcher;
+
+const DELETE_TOKEN_HEADER: &str = "X-Delete-Token";
+
+#[derive(Debug, Serialize)]
+pub struct ShareCreatedResponse {
+    id: String,
+    /// Page with the card meta tags, for posting to social media.
+    url: String,
+    image_url: String,
+    /// Send as `X-Delete-Token` to `DELETE /r/{id}`.
+    delete_token: String,
+    expires_at: String,
+}
+
+/// Stitches a receipt like `POST /receipts` and stores it to be shared. A
+/// receipt already generated from the same uploads is taken from the cache.
+#[post("/share")]
+pub async fn insert(
+    MultipartForm(request): MultipartForm<CreateReceiptRequest>,
+    http_request: HttpRequest,
+    stitcher: web::Data<Stitcher>,
+    upload_limits: web::Data<UploadLimits>,
+    decode_limits: web::Data<DecodeLimits>,
+    bounds: web::Data<StitchOptionBounds>,
+    ocr_config: web::Data<OcrConfig>,
+    cache: web::Data<ResultCache>,
+    share_config: web::Data<ShareConfig>,
+    shares: web::Data<ShareStore>,
+) -> Result<HttpResponse, ApiError> {
+    let receipt = PreparedReceipt::upload(
+        request,
+        &upload_limits,
+        &decode_limits,
+        &bounds,
+        &ocr_config,
+    )?;
+    let format = receipt.output_format(&http_request);
+
+    let (receipt, key) = receipt::cache_key(receipt, "receipt", format).await?;
+    let encoded = receipt::cached_or_generate(
+        &stitcher,
+        cache,
+        receipt,
+        key,
+        format,
+        PreparedReceipt::generate,
+    )
+    .await?;
+    let (width, height) = encoded
+        .dimensions()
+        .ok_or_else(|| ApiError::InvalidParameter {
+            message: "Receipts split into pages cannot be shared".to_string(),
+            sensitive_message: None,
+        })?;
+
+    let (shared, delete_token) = SharedReceipt::new(
+        format.mime().to_string(),
+        format.extension().to_string(),
+        width,
+        height,
+        share_config.ttl,
+    );
+
+    let stored = shared.clone();
+    web::block(move || shares.put(&stored, &encoded.bytes))
+        .await
+        .map_err(storage_error)?
+        .map_err(storage_error)?;
+    info!("Shared receipt {} stored", shared.id);
+
+    Ok(HttpResponse::Created().json(ShareCreatedResponse {
+        url: page_url(&http_request, &shared),
+        image_url: image_url(&http_request, &shared),
+        id: shared.id,
+        delete_token,
+        expires_at: chrono::Utc
+            .timestamp_opt(shared.expires_at, 0)
+            .single()
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default(),
+    }))
+}
+
+/// `/r/{id}` is a page showing the receipt and `/r/{id}.{extension}` the
+/// image itself.
+#[get("/{name}")]
+pub async fn show(
+    path: web::Path<String>,
+    http_request: HttpRequest,
+    shares: web::Data<ShareStore>,
+) -> Result<HttpResponse, ApiError> {
+    let name = path.into_inner();
+    let (id, extension) = match name.split_once('.') {
+        Some((id, extension)) => (id.to_string(), Some(extension.to_string())),
+        None => (name.clone(), None),
+    };
+
+    let shared = find(&shares, &id).await?;
+
+    match extension {
+        None => Ok(HttpResponse::Ok()
+            .insert_header(ContentType::html())
+            .body(render_page(&http_request, &shared))),
+        Some(extension) if extension == shared.extension => {
+            let mime = shared.mime.clone();
+            let image = web::block(move || shares.image(&shared))
+                .await
+                .map_err(storage_error)?
+                .map_err(storage_error)?;
+
+            Ok(HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, mime))
+                .insert_header(CacheControl(vec![
+                    CacheDirective::Public,
+                    CacheDirective::MaxAge(3600),
+                ]))
+                .body(image))
+        }
+        Some(_) => Err(ApiError::SharedReceiptNotFound { id: name }),
+    }
+}
+
+#[delete("/{id}")]
+pub async fn delete(
+    path: web::Path<String>,
+    http_request: HttpRequest,
+    shares: web::Data<ShareStore>,
+) -> Result<HttpResponse, ApiError> {
+    let id = path.into_inner();
+    let shared = find(&shares, &id).await?;
+
+    let token = http_request
+        .headers()
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|t| t.to_str().ok())
+        .unwrap_or_default();
+    if !shared.accepts_delete_token(token) {
+        return Err(ApiError::InvalidDeleteToken);
+    }
+
+    web::block(move || shares.delete(&shared.id))
+        .await
+        .map_err(storage_error)?
+        .map_err(storage_error)?;
+    info!("Shared receipt {} deleted", id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Looks up a receipt that has not expired yet.
+async fn find(shares: &web::Data<ShareStore>, id: &str) -> Result<SharedReceipt, ApiError> {
+    let not_found = || ApiError::SharedReceiptNotFound { id: id.to_string() };
+    if !share::is_valid_id(id) {
+        return Err(not_found());
+    }
+
+    let shares = shares.clone();
+    let owned_id = id.to_string();
+    web::block(move || shares.get(&owned_id))
+        .await
+        .map_err(storage_error)?
+        .map_err(storage_error)?
+        .filter(|r| !r.is_expired())
+        .ok_or_else(not_found)
+}
+
+fn page_url(request: &HttpRequest, shared: &SharedReceipt) -> String {
+    let info = request.connection_info();
+    format!("{}://{}/r/{}", info.scheme(), info.host(), shared.id)
+}
+
+fn image_url(request: &HttpRequest, shared: &SharedReceipt) -> String {
+    format!("{}.{}", page_url(request, shared), shared.extension)
+}
+
+/// A page showing the receipt, with OpenGraph and Twitter card tags so that
+/// links to it are previewed with the image.
+fn render_page(request: &HttpRequest, shared: &SharedReceipt) -> String {
+    let page_url = escape_html(&page_url(request, shared));
+    let image_url = escape_html(&image_url(request, shared));
+
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="ja">
+  <head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <title>うまーじゃー</title>
+    <meta property="og:type" content="website" />
+    <meta property="og:site_name" content="うまーじゃー" />
+    <meta property="og:title" content="ウマ娘詳細" />
+    <meta property="og:url" content="{page_url}" />
+    <meta property="og:image" content="{image_url}" />
+    <meta property="og:image:width" content="{width}" />
+    <meta property="og:image:height" content="{height}" />
+    <meta name="twitter:card" content="summary_large_image" />
+    <meta name="twitter:title" content="ウマ娘詳細" />
+    <meta name="twitter:image" content="{image_url}" />
+    <style>
+      body {{ margin: 0; background-color: #222; color: #eee; text-align: center; }}
+      img {{ max-width: 100%; }}
+      a {{ color: #eee; }}
+    </style>
+  </head>
+  <body>
+    <p><a href="/">うまーじゃーでつくりました</a></p>
+    <img src="{image_url}" alt="ウマ娘詳細" />
+  </body>
+</html>
+"#,
+        page_url = page_url,
+        image_url = image_url,
+        width = shared.width,
+        height = shared.height,
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn storage_error(e: impl std::fmt::Debug) -> ApiError {
+    error!("Shared receipt storage failed: {:?}", e);
+    ApiError::ImageUploadError {
+        message: "Failed to access shared receipts".to_string(),
+    }
+}
@@ -0,0 +1,359 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba, RgbaImage};
+use rusttype::Scale;
+
+use crate::analysis::{self, AnalysisStatus, ImageAnalysis};
+use crate::caption;
+use crate::detail::StitchOptions;
+use crate::error::ApiError;
+
+const SOURCE_STRIPE_WIDTH: u32 = 12;
+const SEAM_THICKNESS: u32 = 3;
+const SEAM_COLOR: Rgba<u8> = Rgba([255, 48, 48, 255]);
+const OVERLAP_COLOR: Rgba<u8> = Rgba([255, 214, 0, 90]);
+const SOURCE_COLORS: [Rgba<u8>; 6] = [
+    Rgba([77, 176, 255, 255]),
+    Rgba([255, 107, 107, 255]),
+    Rgba([120, 220, 120, 255]),
+    Rgba([200, 130, 255, 255]),
+    Rgba([255, 170, 60, 255]),
+    Rgba([60, 220, 220, 255]),
+];
+const SOURCE_GAP: u32 = 24;
+const BOX_THICKNESS: u32 = 3;
+const HEADER_COLOR: Rgba<u8> = Rgba([77, 176, 255, 255]);
+const SCROLL_AREA_COLOR: Rgba<u8> = Rgba([120, 220, 120, 255]);
+const CLOSE_BUTTON_COLOR: Rgba<u8> = Rgba([255, 170, 60, 255]);
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 170]);
+const LABEL_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Stitches the screenshots and annotates the result for debugging.
+///
+/// A colored stripe on the left marks which screenshot each row came from,
+/// a red line marks every seam and a yellow band marks the rows that were
+/// matched against the previous screenshot. The screenshots follow on the
+/// right, scaled to the width of the result, with boxes around the header,
+/// scroll area and close button found in each and a label with how its
+/// scroll area matched the previous one. If a screenshot fails to stitch,
+/// the image stitched so far is returned.
+pub fn render(
+    images: &[DynamicImage],
+    file_names: &[String],
+    options: &StitchOptions,
+) -> Result<DynamicImage, ApiError> {
+    let (analysis, stitched) = analysis::analyze(images, file_names, options);
+    let stitched = stitched.ok_or_else(|| ApiError::ImageGenerateError {
+        message: analysis
+            .images
+            .iter()
+            .find_map(|i| i.error.clone())
+            .unwrap_or_else(|| "Failed to generate image".to_string()),
+    })?;
+
+    let mut stitched = stitched.to_rgba8();
+    annotate_seams(&mut stitched, &analysis.images, options);
+
+    let width = stitched.width();
+    let sources = images
+        .iter()
+        .zip(&analysis.images)
+        .map(|(image, analysis)| annotate_source(image, analysis, width))
+        .collect::<Vec<_>>();
+    let sources_height = sources
+        .iter()
+        .map(|s| s.height() + SOURCE_GAP)
+        .sum::<u32>()
+        .saturating_sub(SOURCE_GAP);
+
+    let mut canvas = RgbaImage::from_pixel(
+        width * 2 + SOURCE_GAP,
+        stitched.height().max(sources_height),
+        Rgba([0, 0, 0, 255]),
+    );
+    image::imageops::replace(&mut canvas, &stitched, 0, 0);
+    let mut y = 0;
+    for source in sources {
+        image::imageops::replace(&mut canvas, &source, (width + SOURCE_GAP) as i64, y);
+        y += (source.height() + SOURCE_GAP) as i64;
+    }
+
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+/// Marks which screenshot each row of the stitched image came from.
+///
+/// Every stitched prefix ends with the close button unless it is trimmed, so
+/// the rows a screenshot added start that much higher up.
+fn annotate_seams(canvas: &mut RgbaImage, images: &[ImageAnalysis], options: &StitchOptions) {
+    let close_button_height = if options.trim_close_button {
+        0
+    } else {
+        images
+            .first()
+            .and_then(|i| Some((i.layout?, i.scaling_factor?)))
+            .and_then(|(layout, scaling_factor)| {
+                let footer_top = layout.footer_top?;
+                Some(((layout.bottom - footer_top) as f64 * scaling_factor).round() as u32)
+            })
+            .unwrap_or_default()
+    };
+    let ok = images
+        .iter()
+        .take_while(|i| i.status == AnalysisStatus::Ok)
+        .count();
+    let mut top = 0;
+
+    for (index, image) in images[..ok].iter().enumerate() {
+        let appended_height = image.appended_height.unwrap_or_default();
+        let (from, height) = if index == ok - 1 {
+            (top, canvas.height().saturating_sub(top))
+        } else if index == 0 {
+            (0, appended_height.saturating_sub(close_button_height))
+        } else {
+            (top, appended_height)
+        };
+
+        if index > 0 {
+            let overlap_height = image.overlap_height.unwrap_or_default();
+            blend_rect(
+                canvas,
+                0,
+                from.saturating_sub(overlap_height),
+                canvas.width(),
+                overlap_height.min(from),
+                OVERLAP_COLOR,
+            );
+            blend_rect(canvas, 0, from, canvas.width(), SEAM_THICKNESS, SEAM_COLOR);
+        }
+
+        blend_rect(
+            canvas,
+            0,
+            from,
+            SOURCE_STRIPE_WIDTH,
+            height,
+            SOURCE_COLORS[index % SOURCE_COLORS.len()],
+        );
+
+        top = from + height;
+    }
+}
+
+/// Scales a screenshot to `width` and outlines the parts of the screen found
+/// in it, with a label giving its index, file name and scroll match.
+fn annotate_source(image: &DynamicImage, analysis: &ImageAnalysis, width: u32) -> RgbaImage {
+    let scale = width as f64 / image.width().max(1) as f64;
+    let height = ((image.height() as f64 * scale).round() as u32).max(1);
+    let mut canvas = image
+        .resize_exact(width, height, FilterType::Triangle)
+        .to_rgba8();
+    let scaled = |value: u32| (value as f64 * scale).round() as u32;
+
+    if let Some(layout) = analysis.layout {
+        let (left, right) = (scaled(layout.left), scaled(layout.right));
+        let footer_top = layout.footer_top.unwrap_or(layout.bottom);
+        let boxes = [
+            (layout.top, layout.title_bottom, HEADER_COLOR),
+            (layout.title_bottom, footer_top, SCROLL_AREA_COLOR),
+            (footer_top, layout.bottom, CLOSE_BUTTON_COLOR),
+        ];
+        for (top, bottom, color) in boxes {
+            if bottom > top {
+                outline_rect(
+                    &mut canvas,
+                    left,
+                    scaled(top),
+                    right - left,
+                    scaled(bottom) - scaled(top),
+                    color,
+                );
+            }
+        }
+    }
+
+    blend_rect(
+        &mut canvas,
+        0,
+        0,
+        SOURCE_STRIPE_WIDTH,
+        height,
+        SOURCE_COLORS[analysis.index % SOURCE_COLORS.len()],
+    );
+
+    let label = match (&analysis.error, analysis.scroll_match, analysis.layout) {
+        _ if analysis.status == AnalysisStatus::Skipped => {
+            format!("#{} {}: skipped", analysis.index, analysis.file_name)
+        }
+        (Some(error), _, _) => format!("#{} {}: {}", analysis.index, analysis.file_name, error),
+        (None, Some(scroll_match), _) => format!(
+            "#{} {}: scrolled {}px, difference {:.2}",
+            analysis.index, analysis.file_name, scroll_match.offset, scroll_match.difference
+        ),
+        (None, None, None) => format!(
+            "#{} {}: no layout found",
+            analysis.index, analysis.file_name
+        ),
+        (None, None, Some(_)) if analysis.index > 0 => {
+            format!(
+                "#{} {}: no scroll match",
+                analysis.index, analysis.file_name
+            )
+        }
+        (None, None, Some(_)) => format!("#{} {}", analysis.index, analysis.file_name),
+    };
+    draw_label(&mut canvas, &label);
+
+    canvas
+}
+
+fn draw_label(canvas: &mut RgbaImage, text: &str) {
+    let font = caption::regular_font();
+    let font_size = (canvas.width() as f32 / 30.0).clamp(12.0, 32.0);
+    let scale = Scale::uniform(font_size);
+    let padding = font_size * 0.4;
+    let label_width = caption::text_advance(font, scale, text) + padding * 2.0;
+
+    blend_rect(
+        canvas,
+        SOURCE_STRIPE_WIDTH,
+        0,
+        label_width.ceil() as u32,
+        (font_size * 1.4 + padding).ceil() as u32,
+        LABEL_BACKGROUND,
+    );
+    caption::draw_text(
+        canvas,
+        font,
+        scale,
+        SOURCE_STRIPE_WIDTH as f32 + padding,
+        padding,
+        LABEL_COLOR,
+        1.0,
+        text,
+    );
+}
+
+fn outline_rect(canvas: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    let thickness = BOX_THICKNESS.min(width).min(height);
+    blend_rect(canvas, x, y, width, thickness, color);
+    blend_rect(
+        canvas,
+        x,
+        (y + height).saturating_sub(thickness),
+        width,
+        thickness,
+        color,
+    );
+    blend_rect(canvas, x, y, thickness, height, color);
+    blend_rect(
+        canvas,
+        (x + width).saturating_sub(thickness),
+        y,
+        thickness,
+        height,
+        color,
+    );
+}
+
+fn blend_rect(canvas: &mut RgbaImage, x: u32, y: u32, width: u32, height: u32, color: Rgba<u8>) {
+    let alpha = color[3] as u32;
+
+    for py in y..(y + height).min(canvas.height()) {
+        for px in x..(x + width).min(canvas.width()) {
+            let pixel = canvas.get_pixel_mut(px, py);
+            for channel in 0..3 {
+                pixel[channel] = ((pixel[channel] as u32 * (255 - alpha)
+                    + color[channel] as u32 * alpha)
+                    / 255) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::{Layout, ScreenType};
+
+    fn analysis(index: usize, appended_height: u32) -> ImageAnalysis {
+        ImageAnalysis {
+            index,
+            file_name: format!("{}.png", index),
+            width: 10,
+            height: 100,
+            status: AnalysisStatus::Ok,
+            scaling_factor: Some(1.0),
+            appended_height: Some(appended_height),
+            overlap_height: (index > 0).then_some(0),
+            layout: Some(Layout {
+                left: 0,
+                right: 10,
+                top: 0,
+                title_bottom: 10,
+                footer_top: Some(90),
+                bottom: 100,
+                title_length: 5,
+            }),
+            scroll_match: None,
+            error: None,
+        }
+    }
+
+    fn stripes(trim_close_button: bool, height: u32) -> Vec<usize> {
+        let options = StitchOptions {
+            trim_margin: false,
+            trim_close_button,
+            trim_title: false,
+            match_threshold: 10,
+            scaling_threshold_pixels: None,
+            screen_type: ScreenType::HorseGirlDetail,
+        };
+        let images = [analysis(0, 50), analysis(1, 20), analysis(2, 30)];
+        let mut canvas = RgbaImage::from_pixel(20, height, Rgba([0, 0, 0, 255]));
+        annotate_seams(&mut canvas, &images, &options);
+
+        (0..height)
+            .map(|y| {
+                SOURCE_COLORS
+                    .iter()
+                    .position(|c| c == canvas.get_pixel(0, y))
+                    .unwrap_or(usize::MAX)
+            })
+            .collect()
+    }
+
+    fn expected(rows: &[(usize, u32)]) -> Vec<usize> {
+        rows.iter()
+            .flat_map(|&(index, count)| vec![index; count as usize])
+            .collect()
+    }
+
+    #[test]
+    fn stripes_rows_by_screenshot_with_the_close_button_trimmed() {
+        let stripes = stripes(true, 100);
+        // Seams cover the first rows of the second and third screenshots.
+        assert_eq!(stripes[..50], expected(&[(0, 50)])[..]);
+        assert_eq!(
+            stripes[50 + SEAM_THICKNESS as usize..70],
+            expected(&[(1, 17)])[..]
+        );
+        assert_eq!(
+            stripes[70 + SEAM_THICKNESS as usize..],
+            expected(&[(2, 27)])[..]
+        );
+    }
+
+    #[test]
+    fn stripes_rows_by_screenshot_above_the_kept_close_button() {
+        let stripes = stripes(false, 100);
+        assert_eq!(stripes[..40], expected(&[(0, 40)])[..]);
+        assert_eq!(
+            stripes[40 + SEAM_THICKNESS as usize..60],
+            expected(&[(1, 17)])[..]
+        );
+        assert_eq!(
+            stripes[60 + SEAM_THICKNESS as usize..],
+            expected(&[(2, 37)])[..]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+/// Side of the difference hash grid. The hash has `HASH_SIZE * HASH_SIZE` bits.
+const HASH_SIZE: u32 = 32;
+/// Screenshots whose hashes differ in at most this many bits are treated as
+/// the same scroll position.
+const MAX_DISTANCE: u32 = 10;
+
+/// Difference hash of a downscaled grayscale image. Close hashes mean
+/// visually near-identical images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerceptualHash(Vec<u64>);
+
+impl PerceptualHash {
+    pub fn of(image: &DynamicImage) -> Self {
+        let gray = image
+            .resize_exact(HASH_SIZE + 1, HASH_SIZE, FilterType::Triangle)
+            .to_luma8();
+
+        let mut bits = vec![0; (HASH_SIZE * HASH_SIZE / 64) as usize];
+        for y in 0..HASH_SIZE {
+            for x in 0..HASH_SIZE {
+                if gray.get_pixel(x, y)[0] > gray.get_pixel(x + 1, y)[0] {
+                    let bit = (y * HASH_SIZE + x) as usize;
+                    bits[bit / 64] |= 1 << (bit % 64);
+                }
+            }
+        }
+
+        Self(bits)
+    }
+
+    pub fn distance(&self, other: &Self) -> u32 {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+}
+
+/// Drops screenshots that are near-identical to one kept before them, such
+/// as the same screenshot picked twice or a frame taken without scrolling.
+/// Returns the kept images and the indices of the removed ones.
+pub fn deduplicate(images: Vec<DynamicImage>) -> (Vec<DynamicImage>, Vec<usize>) {
+    let mut kept: Vec<(PerceptualHash, DynamicImage)> = Vec::with_capacity(images.len());
+    let mut removed = Vec::new();
+
+    for (index, image) in images.into_iter().enumerate() {
+        let hash = PerceptualHash::of(&image);
+
+        if kept.iter().any(|(h, _)| h.distance(&hash) <= MAX_DISTANCE) {
+            removed.push(index);
+        } else {
+            kept.push((hash, image));
+        }
+    }
+
+    (kept.into_iter().map(|(_, image)| image).collect(), removed)
+}
@@ -0,0 +1,53 @@
+use image::DynamicImage;
+use log::info;
+use uma_details_utility::image::detail::{HeaderTrimMode, HorseGirlFullDetailImage, ImageConfig};
+
+use crate::error::ApiError;
+use crate::screen::ScreenType;
+
+/// Options controlling how screenshots are stitched.
+#[derive(Debug, Clone, Copy)]
+pub struct StitchOptions {
+    pub trim_margin: bool,
+    pub trim_close_button: bool,
+    pub trim_title: bool,
+    /// Passed through to `HorseGirlFullDetailImage::from_images`.
+    pub match_threshold: u32,
+    /// `None` keeps the full resolution.
+    pub scaling_threshold_pixels: Option<u32>,
+    pub screen_type: ScreenType,
+}
+
+impl StitchOptions {
+    pub fn image_config(&self) -> ImageConfig {
+        let header_trim_mode = if self.trim_margin {
+            Some(if self.trim_title {
+                HeaderTrimMode::TrimTitleBar
+            } else {
+                HeaderTrimMode::TrimMarginOnly
+            })
+        } else {
+            None
+        };
+
+        ImageConfig {
+            do_merge_close_button: !self.trim_close_button,
+            header_trim_mode,
+            scaling_threshold_pixels: self.scaling_threshold_pixels,
+        }
+    }
+}
+
+/// Stitches decoded screenshots, in order, into a detail image.
+pub fn stitch(
+    images: &[DynamicImage],
+    options: &StitchOptions,
+) -> Result<HorseGirlFullDetailImage, ApiError> {
+    info!("Stitching {} images", images.len());
+
+    Ok(HorseGirlFullDetailImage::from_images(
+        images.to_vec(),
+        options.match_threshold,
+        options.image_config(),
+    )?)
+}
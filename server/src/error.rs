@@ -1,4 +1,5 @@
 use actix_web::body::BoxBody;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
 use derive_more::Display;
@@ -6,6 +7,8 @@ use log::error;
 use serde::Serialize;
 use thiserror::Error;
 
+use crate::job::JobStatus;
+
 #[derive(Debug, Display, Error, Serialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
@@ -36,6 +39,48 @@ pub enum ApiError {
         #[from]
         source: uma_details_utility::image::Error,
     },
+    #[display(fmt = "Too many images, limit is {}", limit)]
+    TooManyImages {
+        limit: usize,
+    },
+    #[display(fmt = "Image {} is too large, limit is {} bytes", index, limit)]
+    ImageTooLarge {
+        index: usize,
+        limit: usize,
+    },
+    #[display(fmt = "Request is too large, limit is {} bytes", limit)]
+    RequestTooLarge {
+        limit: usize,
+    },
+    #[display(fmt = "Images have too many pixels, limit is {}", limit)]
+    PixelBudgetExceeded {
+        limit: u64,
+    },
+    #[display(fmt = "Video is too large, limit is {} bytes", limit)]
+    VideoTooLarge {
+        limit: usize,
+    },
+    #[display(fmt = "Video is too long, limit is {} seconds", limit)]
+    VideoTooLong {
+        limit: u64,
+    },
+    #[display(fmt = "Job {} not found", id)]
+    JobNotFound {
+        id: String,
+    },
+    #[display(fmt = "Job is not finished: {:?}", status)]
+    JobNotFinished {
+        status: JobStatus,
+    },
+    #[display(fmt = "Shared receipt {} not found", id)]
+    SharedReceiptNotFound {
+        id: String,
+    },
+    InvalidDeleteToken,
+    #[display(fmt = "Server busy, retry after {} seconds", retry_after)]
+    ServerBusy {
+        retry_after: u64,
+    },
 }
 
 #[derive(Debug, Display, Serialize)]
@@ -52,11 +97,28 @@ impl ResponseError for ApiError {
             ApiError::ImageUploadError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::ImageGenerateError { .. } => StatusCode::BAD_REQUEST,
             ApiError::ImageProcessFailed { .. } => StatusCode::BAD_REQUEST,
+            ApiError::TooManyImages { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::ImageTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RequestTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::PixelBudgetExceeded { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::VideoTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::VideoTooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::JobNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::JobNotFinished { .. } => StatusCode::CONFLICT,
+            ApiError::SharedReceiptNotFound { .. } => StatusCode::NOT_FOUND,
+            ApiError::InvalidDeleteToken => StatusCode::FORBIDDEN,
+            ApiError::ServerBusy { .. } => StatusCode::SERVICE_UNAVAILABLE,
         }
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
         error!("Responded error: {:?}", self);
-        HttpResponse::build(self.status_code()).json(ApiErrorContainer { error: self })
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let ApiError::ServerBusy { retry_after } = self {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(ApiErrorContainer { error: self })
     }
 }
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::controller::receipt::EncodedReceipt;
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Processing,
+    Done,
+    Failed,
+}
+
+pub enum JobState {
+    Queued,
+    Processing,
+    Done(EncodedReceipt),
+    Failed(ApiError),
+}
+
+impl JobState {
+    pub fn status(&self) -> JobStatus {
+        match self {
+            JobState::Queued => JobStatus::Queued,
+            JobState::Processing => JobStatus::Processing,
+            JobState::Done(..) => JobStatus::Done,
+            JobState::Failed(_) => JobStatus::Failed,
+        }
+    }
+}
+
+struct Job {
+    state: JobState,
+    expires_at: Instant,
+}
+
+/// In-process store of asynchronous receipt jobs.
+///
+/// Every job expires `ttl` after its last state change, finished or not.
+/// Finished receipts are kept encoded, so at most `max_jobs` of them are
+/// held at a time.
+pub struct JobStore {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    ttl: Duration,
+    max_jobs: usize,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration, max_jobs: usize) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            ttl,
+            max_jobs,
+        }
+    }
+
+    /// Fails with [`ApiError::ServerBusy`] until the oldest job expires if
+    /// there are already `max_jobs` live jobs.
+    pub fn create(&self) -> Result<Uuid, ApiError> {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= self.max_jobs {
+            jobs.retain(|_, job| job.expires_at > now);
+        }
+        if jobs.len() >= self.max_jobs {
+            let next_expiry = jobs.values().map(|job| job.expires_at).min();
+            let retry_after = next_expiry.map_or(self.ttl, |t| t.duration_since(now));
+            return Err(ApiError::ServerBusy {
+                retry_after: retry_after.as_secs() + 1,
+            });
+        }
+
+        let id = Uuid::new_v4();
+        jobs.insert(
+            id,
+            Job {
+                state: JobState::Queued,
+                expires_at: Instant::now() + self.ttl,
+            },
+        );
+
+        Ok(id)
+    }
+
+    pub fn update(&self, id: &Uuid, state: JobState) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.state = state;
+            job.expires_at = Instant::now() + self.ttl;
+        }
+    }
+
+    /// Calls `f` with the state of a live job, or returns `None` if the job
+    /// does not exist or has expired.
+    pub fn with<F, R>(&self, id: &Uuid, f: F) -> Option<R>
+    where
+        F: FnOnce(&JobState) -> R,
+    {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.expires_at > Instant::now())
+            .map(|job| f(&job.state))
+    }
+
+    pub fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, job| job.expires_at > now);
+
+        before - jobs.len()
+    }
+}
+
+pub async fn sweep_expired(store: actix_web::web::Data<JobStore>, interval: Duration) {
+    let mut interval = actix_web::rt::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let removed = store.remove_expired();
+        if removed > 0 {
+            info!("Removed {} expired receipt jobs", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_jobs_over_the_limit_until_one_expires() {
+        let store = JobStore::new(Duration::from_millis(50), 2);
+        store.create().unwrap();
+        store.create().unwrap();
+
+        assert!(matches!(
+            store.create(),
+            Err(ApiError::ServerBusy { retry_after: 1 })
+        ));
+
+        std::thread::sleep(Duration::from_millis(60));
+        store.create().unwrap();
+    }
+}
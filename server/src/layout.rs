@@ -0,0 +1,219 @@
+use image::imageops;
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+
+use crate::error::ApiError;
+
+const MAX_COLUMNS: u32 = 6;
+const MAX_GUTTER: u32 = 200;
+const DEFAULT_GUTTER: u32 = 24;
+const DEFAULT_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Share of the width ignored on both sides when looking for row breaks, so
+/// that window frames and scroll bars do not hide them.
+const BREAK_EDGE_RATIO: f32 = 0.05;
+/// Largest difference of a channel from the row's first pixel for a row to
+/// count as blank.
+const BREAK_TOLERANCE: u8 = 6;
+/// Fewest blank rows in a run for it to count as a break between sections.
+const MIN_BREAK_HEIGHT: u32 = 4;
+
+/// Rearranges a tall receipt into side-by-side columns.
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnLayout {
+    pub columns: u32,
+    /// Space between columns and around the edges, in pixels.
+    pub gutter: u32,
+    pub background: Rgba<u8>,
+}
+
+impl ColumnLayout {
+    /// Parses the `columns`, `column_gutter` and `column_background` form
+    /// fields. Returns `None` for a single column.
+    pub fn from_form(
+        columns: Option<u32>,
+        gutter: Option<u32>,
+        background: Option<&str>,
+    ) -> Result<Option<Self>, ApiError> {
+        let columns = columns.unwrap_or(1);
+        if !(1..=MAX_COLUMNS).contains(&columns) {
+            return Err(ApiError::InvalidParameter {
+                message: format!("columns must be between 1 and {}", MAX_COLUMNS),
+                sensitive_message: Some(format!("Invalid columns {}", columns)),
+            });
+        }
+
+        Ok((columns > 1).then_some(Self {
+            columns,
+            gutter: parse_gutter(gutter)?,
+            background: parse_background(background)?,
+        }))
+    }
+
+    /// Cuts the receipt at the section breaks closest to equal heights and
+    /// lays the pieces out left to right. A receipt with too few breaks gets
+    /// fewer columns rather than a cut through a row.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let (width, height) = image.dimensions();
+        let breaks = row_breaks(image);
+
+        let mut cuts = vec![0];
+        for column in 1..self.columns {
+            let target = height * column / self.columns;
+            let previous = *cuts.last().unwrap();
+            let Some(cut) = breaks
+                .iter()
+                .copied()
+                .filter(|&b| b > previous && b < height)
+                .min_by_key(|&b| b.abs_diff(target))
+            else {
+                break;
+            };
+            cuts.push(cut);
+        }
+        cuts.push(height);
+
+        let column_count = cuts.len() as u32 - 1;
+        let column_height = cuts.windows(2).map(|c| c[1] - c[0]).max().unwrap_or(0);
+        let mut canvas = RgbaImage::from_pixel(
+            column_count * width + (column_count + 1) * self.gutter,
+            column_height + 2 * self.gutter,
+            self.background,
+        );
+
+        for (index, cut) in cuts.windows(2).enumerate() {
+            let piece = image.view(0, cut[0], width, cut[1] - cut[0]).to_image();
+            let x = self.gutter + index as u32 * (width + self.gutter);
+            imageops::overlay(&mut canvas, &piece, x as i64, self.gutter as i64);
+        }
+
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Rows where the receipt can be cut without going through a skill or factor
+/// row: the middle of every run of blank rows between sections.
+pub fn row_breaks(image: &DynamicImage) -> Vec<u32> {
+    let image = image.to_rgba8();
+    let (width, height) = image.dimensions();
+    if width == 0 {
+        return Vec::new();
+    }
+    let edge = (width as f32 * BREAK_EDGE_RATIO) as u32;
+    let (left, right) = (edge, width.saturating_sub(edge).max(edge + 1));
+
+    let is_blank = |y: u32| {
+        let first = image.get_pixel(left, y);
+        (left..right).all(|x| {
+            let pixel = image.get_pixel(x, y);
+            pixel
+                .0
+                .iter()
+                .zip(first.0)
+                .all(|(a, b)| a.abs_diff(b) <= BREAK_TOLERANCE)
+        })
+    };
+
+    let mut breaks = Vec::new();
+    let mut run_start = None;
+    for y in 0..=height {
+        match (y < height && is_blank(y), run_start) {
+            (true, None) => run_start = Some(y),
+            (false, Some(start)) => {
+                if y - start >= MIN_BREAK_HEIGHT {
+                    breaks.push(start + (y - start) / 2);
+                }
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    breaks
+}
+
+/// Parses the `column_gutter` form field.
+pub fn parse_gutter(gutter: Option<u32>) -> Result<u32, ApiError> {
+    let gutter = gutter.unwrap_or(DEFAULT_GUTTER);
+    if gutter > MAX_GUTTER {
+        return Err(ApiError::InvalidParameter {
+            message: format!("column_gutter must be at most {}", MAX_GUTTER),
+            sensitive_message: Some(format!("Invalid column_gutter {}", gutter)),
+        });
+    }
+
+    Ok(gutter)
+}
+
+/// Parses the `column_background` form field.
+pub fn parse_background(background: Option<&str>) -> Result<Rgba<u8>, ApiError> {
+    Ok(background
+        .map(|b| {
+            parse_color(b).ok_or_else(|| ApiError::InvalidParameter {
+                message: "column_background must be a color like #ffffff".to_string(),
+                sensitive_message: Some(format!("Invalid column_background {}", b)),
+            })
+        })
+        .transpose()?
+        .unwrap_or(DEFAULT_BACKGROUND))
+}
+
+fn parse_color(value: &str) -> Option<Rgba<u8>> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+
+    Some(Rgba([channel(0)?, channel(2)?, channel(4)?, 255]))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A receipt 100 pixels wide with striped rows of the given heights, 10
+    /// blank rows apart.
+    pub(crate) fn receipt(rows: &[u32]) -> DynamicImage {
+        let height = rows.iter().sum::<u32>() + 10 * (rows.len() as u32 - 1);
+        let mut image = RgbaImage::from_pixel(100, height, DEFAULT_BACKGROUND);
+        let mut top = 0;
+        for row in rows {
+            for y in top..top + row {
+                for x in (0..100).filter(|x| x / 5 % 2 == 0) {
+                    image.put_pixel(x, y, Rgba([40, 40, 40, 255]));
+                }
+            }
+            top += row + 10;
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
+    fn layout(columns: u32) -> ColumnLayout {
+        ColumnLayout {
+            columns,
+            gutter: 0,
+            background: DEFAULT_BACKGROUND,
+        }
+    }
+
+    #[test]
+    fn cuts_at_the_breaks_closest_to_equal_heights() {
+        let image = receipt(&[90, 90, 90, 90]);
+
+        assert_eq!(row_breaks(&image), [95, 195, 295]);
+        // Columns of 95, 200 and 95 rows.
+        assert_eq!(layout(3).apply(&image).dimensions(), (300, 200));
+    }
+
+    #[test]
+    fn does_not_cut_through_rows() {
+        let image = receipt(&[90, 300]);
+
+        // The only break, at row 95, is far from the middle but is still used.
+        assert_eq!(layout(2).apply(&image).dimensions(), (200, 305));
+        // There is no second break, so there is no third column.
+        assert_eq!(layout(3).apply(&image).dimensions(), (200, 305));
+        assert_eq!(layout(2).apply(&receipt(&[400])).dimensions(), (100, 400));
+    }
+}
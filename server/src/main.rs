@@ -1,15 +1,48 @@
 use std::io::Write;
 
 use actix_files::Files;
+use actix_multipart::form::MultipartFormConfig;
+use actix_multipart::MultipartError;
+use actix_web::error::PayloadError;
 use actix_web::{web, App, HttpRequest, HttpServer};
 
+use cache::ResultCache;
+use config::{
+    CacheConfig, DecodeLimits, JobConfig, OcrConfig, ShareConfig, StitchOptionBounds,
+    StitchingConfig, StorageConfig, TempDirConfig, UploadLimits, VideoLimits,
+};
 use error::ApiError;
+use job::JobStore;
+use share::ShareStore;
+use stitcher::Stitcher;
+use temp_dir::TempUploadStore;
 
+mod analysis;
+mod cache;
+mod caption;
+mod comparison;
+mod config;
 mod controller;
+mod debug_overlay;
+mod dedupe;
+mod detail;
 mod error;
+mod job;
+mod layout;
+mod ocr;
+mod ordering;
+mod output;
+mod paging;
+mod redaction;
+mod roster;
 mod route;
-
-const TEMP_UPLOAD_DIRECTORY: &str = "./images-temp";
+mod screen;
+mod share;
+mod stitcher;
+mod storage;
+mod temp_dir;
+mod upload;
+mod video;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
@@ -28,10 +61,53 @@ async fn main() -> anyhow::Result<()> {
         })
         .init();
 
+    let temp_dir_config = TempDirConfig::from_env()?;
     log::info!("Creating temp upload dir");
-    std::fs::create_dir_all(TEMP_UPLOAD_DIRECTORY)?;
+    let temp_uploads = web::Data::new(TempUploadStore::new(&temp_dir_config.directory)?);
     log::info!("Temp upload dir created");
 
+    let (removed, reclaimed) = temp_uploads.sweep(None)?;
+    log::info!(
+        "Removed {} leftover temp upload entries, reclaimed {} bytes",
+        removed,
+        reclaimed
+    );
+
+    actix_web::rt::spawn(temp_dir::sweep_periodically(
+        temp_uploads.clone(),
+        temp_dir_config.max_age,
+        temp_dir_config.sweep_interval,
+    ));
+
+    let stitching_config = StitchingConfig::from_env()?;
+    log::info!(
+        "Stitching with {} workers and queue depth {}",
+        stitching_config.workers,
+        stitching_config.queue_depth
+    );
+    let stitcher = web::Data::new(Stitcher::new(&stitching_config));
+
+    let upload_limits = web::Data::new(UploadLimits::from_env()?);
+    let decode_limits = web::Data::new(DecodeLimits::from_env()?);
+    let video_limits = web::Data::new(VideoLimits::from_env()?);
+    let max_request_bytes = upload_limits.max_request_bytes;
+    let stitch_option_bounds = web::Data::new(StitchOptionBounds::from_env()?);
+    let ocr_config = web::Data::new(OcrConfig::from_env()?);
+
+    let job_config = JobConfig::from_env()?;
+    let jobs = web::Data::new(JobStore::new(job_config.ttl, job_config.max_jobs));
+    actix_web::rt::spawn(job::sweep_expired(jobs.clone(), job_config.sweep_interval));
+
+    let result_cache = web::Data::new(ResultCache::new(&CacheConfig::from_env()?)?);
+
+    let share_config = web::Data::new(ShareConfig::from_env()?);
+    let storage = storage::from_config(&StorageConfig::from_env()?)?;
+    let shares = web::Data::new(ShareStore::new(storage));
+    actix_web::rt::spawn(share::sweep_expired(
+        shares.clone(),
+        share_config.sweep_interval,
+    ));
+
     let request_error_handler =
         |err: actix_web_validator::Error, _req: &HttpRequest| -> actix_web::Error {
             {
@@ -46,6 +122,17 @@ async fn main() -> anyhow::Result<()> {
     Ok(HttpServer::new(move || {
         App::new()
             .wrap(actix_web::middleware::Logger::default())
+            .app_data(stitcher.clone())
+            .app_data(jobs.clone())
+            .app_data(temp_uploads.clone())
+            .app_data(upload_limits.clone())
+            .app_data(decode_limits.clone())
+            .app_data(video_limits.clone())
+            .app_data(stitch_option_bounds.clone())
+            .app_data(ocr_config.clone())
+            .app_data(result_cache.clone())
+            .app_data(share_config.clone())
+            .app_data(shares.clone())
             .app_data(
                 actix_web_validator::QueryConfig::default().error_handler(request_error_handler),
             )
@@ -61,10 +148,26 @@ async fn main() -> anyhow::Result<()> {
                     .error_handler(request_error_handler),
             )
             .app_data(
-                actix_multipart::form::tempfile::TempFileConfig::default()
-                    .directory(TEMP_UPLOAD_DIRECTORY),
+                MultipartFormConfig::default()
+                    .total_limit(max_request_bytes)
+                    .memory_limit(max_request_bytes)
+                    .error_handler(move |err, _req| match err {
+                        MultipartError::Payload(PayloadError::Overflow) => {
+                            ApiError::RequestTooLarge {
+                                limit: max_request_bytes,
+                            }
+                            .into()
+                        }
+                        err => ApiError::InvalidParameter {
+                            message: "Invalid multipart form".to_string(),
+                            sensitive_message: Some(err.to_string()),
+                        }
+                        .into(),
+                    }),
             )
             .configure(route::receipts)
+            .configure(route::config)
+            .configure(route::shares)
             .service(Files::new("/", "./dist/").index_file("index.html"))
             .default_service(web::route().to(route::not_found))
     })
@@ -0,0 +1,600 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::ops::RangeInclusive;
+
+use image::{DynamicImage, ImageOutputFormat};
+use leptess::LepTess;
+use log::error;
+use serde::Serialize;
+
+use crate::config::OcrConfig;
+use crate::error::ApiError;
+use crate::redaction::Region;
+
+const STAT_LABELS: [&str; 5] = ["スピード", "スタミナ", "パワー", "根性", "賢さ"];
+const GRADES: &str = "SABCDEFG";
+const SKILLS_HEADER: &str = "スキル";
+const FACTORS_HEADERS: [&str; 2] = ["因子", "継承"];
+const TRAINING_HEADER: &str = "育成情報";
+/// Words with a lower confidence are dropped.
+const MIN_CONFIDENCE: f32 = 30.0;
+/// Labels next to or above the trainer name, ID and friend code.
+const PERSONAL_INFO_LABELS: [&str; 4] = [
+    "トレーナー名",
+    "トレーナーID",
+    "フレンドID",
+    "フレンドコード",
+];
+/// Trainer IDs and friend codes are this many digits long, with or without
+/// spaces between groups.
+const ID_DIGITS: RangeInclusive<usize> = 9..=12;
+
+/// What could be read from a stitched detail image. Fields that were not
+/// found are left empty rather than failing the whole request.
+#[derive(Debug, Default, Serialize)]
+pub struct HorseGirlDetails {
+    pub name: Option<String>,
+    pub stats: Stats,
+    pub aptitudes: Aptitudes,
+    pub skills: Vec<Skill>,
+    pub factors: Vec<Factor>,
+    /// Every line that was read, for checking what the fields came from.
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub speed: Option<u32>,
+    pub stamina: Option<u32>,
+    pub power: Option<u32>,
+    pub guts: Option<u32>,
+    pub wisdom: Option<u32>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Aptitudes {
+    pub turf: Option<char>,
+    pub dirt: Option<char>,
+    pub short: Option<char>,
+    pub mile: Option<char>,
+    pub middle: Option<char>,
+    pub long: Option<char>,
+    pub runner: Option<char>,
+    pub leader: Option<char>,
+    pub betweener: Option<char>,
+    pub chaser: Option<char>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Skill {
+    pub name: String,
+    pub level: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Factor {
+    pub name: String,
+    pub stars: Option<u32>,
+}
+
+/// Runs OCR on a stitched detail image and picks the known fields out of
+/// the text.
+pub fn recognize(image: &DynamicImage, config: &OcrConfig) -> Result<HorseGirlDetails, ApiError> {
+    let lines = read_lines(image, config).map_err(|e| {
+        error!("OCR failed: {:?}", e);
+        ApiError::ImageGenerateError {
+            message: "Failed to read text from image".to_string(),
+        }
+    })?;
+
+    Ok(parse_details(lines))
+}
+
+/// Runs OCR on a stitched image and finds where the trainer name, ID and
+/// friend code are, to be redacted.
+pub fn find_personal_info(
+    image: &DynamicImage,
+    config: &OcrConfig,
+) -> Result<Vec<Region>, ApiError> {
+    let tsv = read_tsv(image, config).map_err(|e| {
+        error!("OCR failed: {:?}", e);
+        ApiError::ImageGenerateError {
+            message: "Failed to read text from image".to_string(),
+        }
+    })?;
+
+    Ok(personal_info_from_tsv(&tsv))
+}
+
+/// Picks the known fields out of the lines read from a detail image.
+fn parse_details(lines: Vec<String>) -> HorseGirlDetails {
+    HorseGirlDetails {
+        name: find_name(&lines),
+        stats: find_stats(&lines),
+        aptitudes: find_aptitudes(&lines),
+        skills: section(&lines, &[SKILLS_HEADER], &FACTORS_HEADERS)
+            .iter()
+            .map(|l| parse_skill(l))
+            .collect(),
+        factors: section(&lines, &FACTORS_HEADERS, &[TRAINING_HEADER, SKILLS_HEADER])
+            .iter()
+            .map(|l| parse_factor(l))
+            .collect(),
+        lines,
+    }
+}
+
+/// Reads the image into lines of text, top to bottom. Skills and factors are
+/// laid out in two columns, so each half of the image is read as separate
+/// lines.
+fn read_lines(image: &DynamicImage, config: &OcrConfig) -> anyhow::Result<Vec<String>> {
+    Ok(lines_from_tsv(&read_tsv(image, config)?, image.width()))
+}
+
+/// Reads the image into Tesseract's TSV output, one row per word with its
+/// position.
+fn read_tsv(image: &DynamicImage, config: &OcrConfig) -> anyhow::Result<String> {
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+
+    let mut tesseract = LepTess::new(Some(&config.model_directory), &config.language)?;
+    tesseract.set_image_from_mem(&png)?;
+
+    Ok(tesseract.get_tsv_text(0)?)
+}
+
+/// Puts the words of Tesseract's TSV output for an image `width` pixels wide
+/// back together into lines.
+fn lines_from_tsv(tsv: &str, width: u32) -> Vec<String> {
+    let middle = width as i32 / 2;
+    // Keyed by the block, paragraph and line Tesseract put the words in, and
+    // by the half of the image they are in.
+    let mut lines: HashMap<(i32, i32, i32, bool), TextLine> = HashMap::new();
+
+    for ((block, paragraph, line_number), word) in tsv_words(tsv) {
+        let right = word.left >= middle;
+        let line = lines
+            .entry((block, paragraph, line_number, right))
+            .or_insert(TextLine {
+                top: word.top,
+                right,
+                words: Vec::new(),
+            });
+        line.top = line.top.min(word.top);
+        line.words.push((word.left, word.text));
+    }
+
+    let mut lines = lines.into_values().collect::<Vec<_>>();
+    lines.sort_by_key(|l| (l.top, l.right));
+
+    lines
+        .into_iter()
+        .map(|mut line| {
+            line.words.sort_by_key(|(left, _)| *left);
+            join_words(line.words.into_iter().map(|(_, w)| w))
+        })
+        .collect()
+}
+
+/// A word read by Tesseract, with its bounding box in pixels.
+struct Word {
+    left: i32,
+    top: i32,
+    width: i32,
+    height: i32,
+    text: String,
+}
+
+/// The confident words of Tesseract's TSV output, keyed by the block,
+/// paragraph and line Tesseract put them in.
+fn tsv_words(tsv: &str) -> Vec<((i32, i32, i32), Word)> {
+    let mut words = Vec::new();
+
+    for row in tsv.lines().skip(1) {
+        let columns = row.split('\t').collect::<Vec<_>>();
+        if columns.len() < 12 || columns[0] != "5" {
+            continue;
+        }
+
+        let number = |i: usize| columns[i].parse::<i32>().unwrap_or_default();
+        let confidence = columns[10].parse::<f32>().unwrap_or_default();
+        let text = columns[11].trim();
+        if text.is_empty() || confidence < MIN_CONFIDENCE {
+            continue;
+        }
+
+        words.push((
+            (number(2), number(3), number(4)),
+            Word {
+                left: number(6),
+                top: number(7),
+                width: number(8),
+                height: number(9),
+                text: text.to_string(),
+            },
+        ));
+    }
+
+    words
+}
+
+/// Finds the trainer name, ID and friend code in Tesseract's TSV output:
+/// whatever follows one of [`PERSONAL_INFO_LABELS`] on its line, or the
+/// line under the label if nothing does, and any run of [`ID_DIGITS`]
+/// digits.
+fn personal_info_from_tsv(tsv: &str) -> Vec<Region> {
+    let mut lines: BTreeMap<(i32, i32, i32), Vec<Word>> = BTreeMap::new();
+    for (key, word) in tsv_words(tsv) {
+        lines.entry(key).or_default().push(word);
+    }
+    let mut lines = lines
+        .into_iter()
+        .map(|((block, ..), mut words)| {
+            words.sort_by_key(|w| w.left);
+            (block, words)
+        })
+        .collect::<Vec<_>>();
+    lines.sort_by_key(|(_, words)| words.iter().map(|w| w.top).min());
+
+    let mut regions = Vec::new();
+    for (index, (block, words)) in lines.iter().enumerate() {
+        if let Some(value) = label_end(words) {
+            let value = words[value..]
+                .iter()
+                .filter(|w| !normalize(&w.text).is_empty())
+                .collect::<Vec<_>>();
+            if !value.is_empty() {
+                regions.extend(bounds(&value));
+            } else if let Some((_, below)) = lines[index + 1..].iter().find(|(b, _)| b == block) {
+                regions.extend(bounds(&below.iter().collect::<Vec<_>>()));
+            }
+        }
+
+        let mut run: Vec<&Word> = Vec::new();
+        for word in words {
+            let digits = normalize(&word.text);
+            let is_digits = !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+            let close = run.last().map_or(true, |w| {
+                word.left - (w.left + w.width) <= w.height.max(word.height)
+            });
+            if !is_digits || !close {
+                regions.extend(id_run(&run));
+                run.clear();
+            }
+            if is_digits {
+                run.push(word);
+            }
+        }
+        regions.extend(id_run(&run));
+    }
+
+    regions.sort_by_key(|r| (r.y, r.x, r.width, r.height));
+    regions.dedup();
+    regions
+}
+
+/// The index of the word after a label in a line, or of the word the label
+/// ends in if the value is written right after it.
+fn label_end(words: &[Word]) -> Option<usize> {
+    for start in 0..words.len() {
+        let mut text = String::new();
+        for (index, word) in words.iter().enumerate().skip(start) {
+            text.push_str(&normalize(&word.text));
+            for label in PERSONAL_INFO_LABELS {
+                if text == label {
+                    return Some(index + 1);
+                }
+                if text.starts_with(label) {
+                    return Some(index);
+                }
+            }
+            if !PERSONAL_INFO_LABELS.iter().any(|l| l.starts_with(&text)) {
+                break;
+            }
+        }
+    }
+
+    None
+}
+
+fn id_run(run: &[&Word]) -> Option<Region> {
+    let digits = run.iter().map(|w| normalize(&w.text).len()).sum::<usize>();
+    if ID_DIGITS.contains(&digits) {
+        bounds(run)
+    } else {
+        None
+    }
+}
+
+/// The box around words, with a margin of a quarter of their height.
+fn bounds(words: &[&Word]) -> Option<Region> {
+    let left = words.iter().map(|w| w.left).min()?;
+    let top = words.iter().map(|w| w.top).min()?;
+    let right = words.iter().map(|w| w.left + w.width).max()?;
+    let bottom = words.iter().map(|w| w.top + w.height).max()?;
+    let margin = words.iter().map(|w| w.height).max()? / 4;
+    let (left, top) = ((left - margin).max(0), (top - margin).max(0));
+
+    Some(Region {
+        x: left as u32,
+        y: top as u32,
+        width: (right + margin - left).max(0) as u32,
+        height: (bottom + margin - top).max(0) as u32,
+    })
+}
+
+/// Turns full-width letters and digits into ASCII and drops spaces and
+/// colons, so labels and IDs compare the same however they were read.
+fn normalize(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            c => c,
+        })
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect()
+}
+
+struct TextLine {
+    top: i32,
+    /// Whether the line is in the right half of the image.
+    right: bool,
+    /// Words with their left edge.
+    words: Vec<(i32, String)>,
+}
+
+/// Joins words, keeping spaces only between Latin words. Japanese text is
+/// read as one word per character.
+fn join_words(words: impl Iterator<Item = String>) -> String {
+    let mut line = String::new();
+
+    for word in words {
+        let needs_space = line
+            .chars()
+            .last()
+            .map_or(false, |c| c.is_ascii_alphanumeric())
+            && word
+                .chars()
+                .next()
+                .map_or(false, |c| c.is_ascii_alphanumeric());
+        if needs_space {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+
+    line
+}
+
+/// The name follows the outfit title in brackets, like
+/// `[スペシャルドリーマー]スペシャルウィーク`.
+fn find_name(lines: &[String]) -> Option<String> {
+    lines.iter().find_map(|line| {
+        let (_, name) = line.split_once([']', '］', '】'])?;
+        let name = name.trim();
+
+        (!name.is_empty()).then(|| name.to_string())
+    })
+}
+
+/// Stat values are on the line below their labels.
+fn find_stats(lines: &[String]) -> Stats {
+    let Some(index) = lines
+        .iter()
+        .position(|l| STAT_LABELS.iter().filter(|s| l.contains(*s)).count() >= 3)
+    else {
+        return Stats::default();
+    };
+
+    let mut values = lines[index..]
+        .iter()
+        .take(3)
+        .flat_map(|l| numbers(l))
+        .filter(|v| *v <= 9999);
+
+    Stats {
+        speed: values.next(),
+        stamina: values.next(),
+        power: values.next(),
+        guts: values.next(),
+        wisdom: values.next(),
+    }
+}
+
+fn find_aptitudes(lines: &[String]) -> Aptitudes {
+    let text = lines.join("\n");
+    let grade = |label: &str| {
+        text.match_indices(label).find_map(|(index, _)| {
+            text[index + label.len()..]
+                .trim_start()
+                .chars()
+                .next()
+                .filter(|c| GRADES.contains(*c))
+        })
+    };
+
+    Aptitudes {
+        turf: grade("芝"),
+        dirt: grade("ダート"),
+        short: grade("短距離"),
+        mile: grade("マイル"),
+        middle: grade("中距離"),
+        long: grade("長距離"),
+        runner: grade("逃げ"),
+        leader: grade("先行"),
+        betweener: grade("差し"),
+        chaser: grade("追込"),
+    }
+}
+
+/// Lines after the first line equal to one of `headers`, up to one of `ends`.
+fn section<'a>(lines: &'a [String], headers: &[&str], ends: &[&str]) -> &'a [String] {
+    let Some(start) = lines.iter().position(|l| headers.contains(&l.as_str())) else {
+        return &[];
+    };
+
+    let lines = &lines[start + 1..];
+    let end = lines
+        .iter()
+        .position(|l| ends.contains(&l.as_str()))
+        .unwrap_or(lines.len());
+
+    &lines[..end]
+}
+
+/// Splits a trailing `Lv3` off a skill name.
+fn parse_skill(line: &str) -> Skill {
+    let lower = line.to_ascii_lowercase();
+    match lower.rfind("lv") {
+        Some(index) => Skill {
+            name: line[..index].trim().to_string(),
+            level: numbers(&line[index..]).next(),
+        },
+        None => Skill {
+            name: line.trim().to_string(),
+            level: None,
+        },
+    }
+}
+
+/// Stars are read as `★` when the icons are recognized at all.
+fn parse_factor(line: &str) -> Factor {
+    let stars = line.chars().filter(|c| *c == '★').count() as u32;
+
+    Factor {
+        name: line.replace(['★', '☆'], "").trim().to_string(),
+        stars: (stars > 0).then_some(stars),
+    }
+}
+
+fn numbers(line: &str) -> impl Iterator<Item = u32> + '_ {
+    line.split(|c: char| !c.is_ascii_digit())
+        .filter_map(|n| n.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/ocr");
+
+    /// Lines read from a screenshot, and the details expected from them.
+    fn fixture(name: &str) -> (Vec<String>, serde_json::Value) {
+        let lines = std::fs::read_to_string(format!("{}/{}.txt", FIXTURES, name)).unwrap();
+        let expected = std::fs::read_to_string(format!("{}/{}.json", FIXTURES, name)).unwrap();
+
+        (
+            lines.lines().map(String::from).collect(),
+            serde_json::from_str(&expected).unwrap(),
+        )
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_fixtures() {
+        for name in ["special_week", "gold_ship", "not_a_detail"] {
+            let (lines, expected) = fixture(name);
+            let details = serde_json::to_value(parse_details(lines)).unwrap();
+
+            assert_eq!(details, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn joins_tsv_words_into_lines_per_column() {
+        let tsv = std::fs::read_to_string(format!("{}/two_columns.tsv", FIXTURES)).unwrap();
+        let expected = std::fs::read_to_string(format!("{}/two_columns.txt", FIXTURES)).unwrap();
+
+        assert_eq!(
+            lines_from_tsv(&tsv, 1000),
+            expected.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn finds_personal_info_in_tsv() {
+        let tsv = std::fs::read_to_string(format!("{}/personal_info.tsv", FIXTURES)).unwrap();
+        let region = |x, y, width, height| Region {
+            x,
+            y,
+            width,
+            height,
+        };
+
+        assert_eq!(
+            personal_info_from_tsv(&tsv),
+            vec![
+                // The name after トレーナー名, without the colon.
+                region(253, 93, 134, 44),
+                // The ID under トレーナーID, also found as a run of digits.
+                region(33, 193, 214, 44),
+                // The friend code written in the same word as its label.
+                region(153, 353, 264, 44),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_stats_from_up_to_two_lines_below_the_labels() {
+        let stats = find_stats(&lines(&[
+            "スピード スタミナ パワー",
+            "1200 800 12000 950",
+            "根性 賢さ 600",
+            "400",
+        ]));
+
+        assert_eq!(stats.speed, Some(1200));
+        assert_eq!(stats.stamina, Some(800));
+        assert_eq!(stats.power, Some(950));
+        assert_eq!(stats.guts, Some(600));
+        assert_eq!(stats.wisdom, None);
+
+        assert!(find_stats(&lines(&["スピード ★★★", "1200"]))
+            .speed
+            .is_none());
+    }
+
+    #[test]
+    fn reads_the_first_grade_after_each_aptitude() {
+        let aptitudes = find_aptitudes(&lines(&["中距離", "★★", "芝 A ダート", "X 中距離 S"]));
+
+        assert_eq!(aptitudes.turf, Some('A'));
+        assert_eq!(aptitudes.dirt, None);
+        assert_eq!(aptitudes.middle, Some('S'));
+    }
+
+    #[test]
+    fn takes_lines_between_headers() {
+        let text = lines(&["育成情報", "継承", "スピード", "スキル", "因子", "賢さ"]);
+
+        assert_eq!(
+            section(&text, &FACTORS_HEADERS, &[SKILLS_HEADER]),
+            ["スピード"]
+        );
+        assert_eq!(section(&text, &["因子"], &[SKILLS_HEADER]), ["賢さ"]);
+        assert!(section(&text, &["サポート"], &[]).is_empty());
+    }
+
+    #[test]
+    fn splits_skill_levels() {
+        let skill = parse_skill("弧線のプロフェッサー lv 3");
+        assert_eq!(skill.name, "弧線のプロフェッサー");
+        assert_eq!(skill.level, Some(3));
+
+        let skill = parse_skill(" 全身全霊 ");
+        assert_eq!(skill.name, "全身全霊");
+        assert_eq!(skill.level, None);
+    }
+
+    #[test]
+    fn counts_factor_stars() {
+        let factor = parse_factor("パワー ★★☆");
+        assert_eq!(factor.name, "パワー");
+        assert_eq!(factor.stars, Some(2));
+
+        assert_eq!(parse_factor("URAシナリオ").stars, None);
+    }
+}
@@ -0,0 +1,109 @@
+use image::DynamicImage;
+use log::debug;
+
+use crate::detail::StitchOptions;
+use crate::error::ApiError;
+use crate::screen::ScrollProfiles;
+
+/// Works out the top-to-bottom order of unordered screenshots.
+///
+/// The scroll area of every screenshot is compared with every other one's by
+/// their [`ScrollProfiles`], and each ordered pair that lines up is scored by
+/// how many rows they share. Pairs are then linked
+/// greedily from the strongest overlap down, which yields one or more chains
+/// that are concatenated in the order of their first uploaded screenshot.
+///
+/// This makes `n * (n - 1)` comparisons, so callers must keep `images` to
+/// `UploadLimits::max_ordered_images`.
+pub fn order(images: &[DynamicImage], options: &StitchOptions) -> Result<Vec<usize>, ApiError> {
+    let count = images.len();
+    if count < 2 {
+        return Ok((0..count).collect());
+    }
+
+    let profiles =
+        ScrollProfiles::new(images, options).ok_or_else(|| ApiError::InvalidParameter {
+            message: "Screenshots to order must all be the same size and show the same screen"
+                .to_string(),
+            sensitive_message: None,
+        })?;
+
+    let mut pairs = Vec::new();
+    for upper in 0..count {
+        for lower in 0..count {
+            if upper == lower {
+                continue;
+            }
+
+            if let Some(scroll_match) = profiles.compare(upper, lower) {
+                debug!("Match of {} -> {}: {:?}", upper, lower, scroll_match);
+                pairs.push((scroll_match.overlap, upper, lower));
+            }
+        }
+    }
+    pairs.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut next = vec![None; count];
+    let mut previous = vec![None; count];
+    for (_, upper, lower) in pairs {
+        if next[upper].is_some()
+            || previous[lower].is_some()
+            || chain_head(&previous, upper) == lower
+        {
+            continue;
+        }
+
+        next[upper] = Some(lower);
+        previous[lower] = Some(upper);
+    }
+
+    let mut order = Vec::with_capacity(count);
+    for head in (0..count).filter(|&i| previous[i].is_none()) {
+        let mut current = Some(head);
+        while let Some(index) = current {
+            order.push(index);
+            current = next[index];
+        }
+    }
+
+    Ok(order)
+}
+
+fn chain_head(previous: &[Option<usize>], mut index: usize) -> usize {
+    while let Some(p) = previous[index] {
+        index = p;
+    }
+
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::screen::tests::{content, options, screenshot};
+    use crate::screen::ScreenType;
+
+    #[test]
+    fn orders_shuffled_screenshots() {
+        let content = content(800);
+        let screenshots = [300, 0, 400, 150]
+            .iter()
+            .map(|&offset| screenshot(ScreenType::HorseGirlDetail, &content, offset))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            order(&screenshots, &options(ScreenType::HorseGirlDetail, false)).unwrap(),
+            vec![1, 3, 0, 2]
+        );
+    }
+
+    #[test]
+    fn rejects_screenshots_without_a_layout() {
+        let screenshots = vec![DynamicImage::new_rgb8(100, 200); 2];
+
+        assert!(matches!(
+            order(&screenshots, &options(ScreenType::HorseGirlDetail, false)),
+            Err(ApiError::InvalidParameter { .. })
+        ));
+    }
+}
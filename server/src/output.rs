@@ -0,0 +1,153 @@
+use std::io::Cursor;
+
+use actix_web::http::header::{self, Header};
+use actix_web::HttpRequest;
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::{ColorType, DynamicImage, ImageEncoder, ImageOutputFormat, ImageResult};
+use mime::Mime;
+
+use crate::error::ApiError;
+
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+const DEFAULT_AVIF_QUALITY: u8 = 80;
+const AVIF_SPEED: u8 = 6;
+
+/// Encoding of the generated receipt image.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    /// PNG re-compressed with the slowest, strongest settings.
+    OptimizedPng,
+    /// Lossless WebP.
+    WebP,
+    Jpeg {
+        quality: u8,
+    },
+    Avif {
+        quality: u8,
+    },
+}
+
+impl OutputFormat {
+    /// Parses the `output_format` form field. `quality` only applies to
+    /// lossy formats and ranges from 1 to 100.
+    pub fn from_form(name: &str, quality: Option<u8>) -> Result<Self, ApiError> {
+        if let Some(quality) = quality {
+            if !(1..=100).contains(&quality) {
+                return Err(ApiError::InvalidParameter {
+                    message: "Quality must be between 1 and 100".to_string(),
+                    sensitive_message: Some(format!("Invalid quality {}", quality)),
+                });
+            }
+        }
+
+        match name {
+            "png" => Ok(OutputFormat::Png),
+            "png_optimized" => Ok(OutputFormat::OptimizedPng),
+            "webp" => Ok(OutputFormat::WebP),
+            "jpeg" => Ok(OutputFormat::Jpeg {
+                quality: quality.unwrap_or(DEFAULT_JPEG_QUALITY),
+            }),
+            "avif" => Ok(OutputFormat::Avif {
+                quality: quality.unwrap_or(DEFAULT_AVIF_QUALITY),
+            }),
+            _ => Err(ApiError::InvalidParameter {
+                message: "Unsupported output format".to_string(),
+                sensitive_message: Some(format!("Output format {} is not supported", name)),
+            }),
+        }
+    }
+
+    /// Picks the most preferred supported image type from the `Accept`
+    /// header. Wildcards are ignored so that browsers sending `*/*` keep
+    /// getting PNG.
+    pub fn negotiate(request: &HttpRequest) -> Option<Self> {
+        let accept = header::Accept::parse(request).ok()?;
+
+        accept
+            .ranked()
+            .into_iter()
+            .find_map(|mime| match mime.essence_str() {
+                "image/png" => Some(OutputFormat::Png),
+                "image/webp" => Some(OutputFormat::WebP),
+                "image/jpeg" => Some(OutputFormat::Jpeg {
+                    quality: DEFAULT_JPEG_QUALITY,
+                }),
+                "image/avif" => Some(OutputFormat::Avif {
+                    quality: DEFAULT_AVIF_QUALITY,
+                }),
+                _ => None,
+            })
+    }
+
+    pub fn mime(&self) -> Mime {
+        match self {
+            OutputFormat::Png | OutputFormat::OptimizedPng => mime::IMAGE_PNG,
+            OutputFormat::WebP => "image/webp".parse().unwrap(),
+            OutputFormat::Jpeg { .. } => mime::IMAGE_JPEG,
+            OutputFormat::Avif { .. } => "image/avif".parse().unwrap(),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png | OutputFormat::OptimizedPng => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Jpeg { .. } => "jpg",
+            OutputFormat::Avif { .. } => "avif",
+        }
+    }
+
+    pub fn encode(&self, image: &DynamicImage) -> ImageResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let (width, height) = (image.width(), image.height());
+
+        match *self {
+            OutputFormat::Png => {
+                image.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)?;
+            }
+            OutputFormat::OptimizedPng => {
+                let rgba = image.to_rgba8();
+                PngEncoder::new_with_quality(
+                    &mut bytes,
+                    CompressionType::Best,
+                    FilterType::Adaptive,
+                )
+                .write_image(rgba.as_raw(), width, height, ColorType::Rgba8)?;
+            }
+            OutputFormat::WebP => {
+                let rgba = image.to_rgba8();
+                WebPEncoder::new_with_quality(&mut bytes, WebPQuality::lossless()).write_image(
+                    rgba.as_raw(),
+                    width,
+                    height,
+                    ColorType::Rgba8,
+                )?;
+            }
+            OutputFormat::Jpeg { quality } => {
+                let rgb = image.to_rgb8();
+                JpegEncoder::new_with_quality(&mut bytes, quality).write_image(
+                    rgb.as_raw(),
+                    width,
+                    height,
+                    ColorType::Rgb8,
+                )?;
+            }
+            OutputFormat::Avif { quality } => {
+                let rgba = image.to_rgba8();
+                AvifEncoder::new_with_speed_quality(&mut bytes, AVIF_SPEED, quality).write_image(
+                    rgba.as_raw(),
+                    width,
+                    height,
+                    ColorType::Rgba8,
+                )?;
+            }
+        }
+
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{Cursor, Write};
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::error::ApiError;
+use crate::layout;
+use crate::output::OutputFormat;
+
+/// Limits of the site the pages are posted to.
+#[derive(Debug, Clone, Copy)]
+pub struct PagePreset {
+    pub max_pages: usize,
+    /// Largest height of a page relative to its width.
+    pub max_aspect_ratio: f32,
+    pub max_pixels: u64,
+}
+
+impl PagePreset {
+    /// Parses the `pages` form field.
+    pub fn from_form(name: &str) -> Result<Self, ApiError> {
+        match name {
+            "x" => Ok(Self {
+                max_pages: 4,
+                max_aspect_ratio: 2.0,
+                max_pixels: 4096 * 4096,
+            }),
+            "instagram" => Ok(Self {
+                max_pages: 10,
+                max_aspect_ratio: 1.25,
+                max_pixels: 1080 * 1350,
+            }),
+            _ => Err(ApiError::InvalidParameter {
+                message: "Unsupported page preset".to_string(),
+                sensitive_message: Some(format!("Page preset {} is not supported", name)),
+            }),
+        }
+    }
+
+    /// Cuts the receipt into pages from top to bottom, shrinking it first if
+    /// a full-height page would have too many pixels. Pages end at the last
+    /// section break that fits, so skill and factor rows stay whole; a row
+    /// taller than a page is rejected rather than cut.
+    pub fn split(&self, image: &DynamicImage) -> Result<Vec<DynamicImage>, ApiError> {
+        let max_width = (self.max_pixels as f64 / self.max_aspect_ratio as f64).sqrt() as u32;
+        let image = if image.width() > max_width {
+            let height = (image.height() as u64 * max_width as u64 / image.width() as u64) as u32;
+            image.resize_exact(max_width, height, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+
+        let height = image.height();
+        let page_height = ((image.width() as f32 * self.max_aspect_ratio) as u32).max(1);
+        let breaks = layout::row_breaks(&image);
+
+        let mut cuts = vec![0];
+        let mut top = 0;
+        while height - top > page_height {
+            let cut = breaks
+                .iter()
+                .copied()
+                .rev()
+                .find(|&b| b > top && b <= top + page_height)
+                .ok_or_else(|| ApiError::InvalidParameter {
+                    message: "A row of the receipt is taller than a page, try more columns"
+                        .to_string(),
+                    sensitive_message: Some(format!(
+                        "No row break between {} and {}",
+                        top,
+                        top + page_height
+                    )),
+                })?;
+            cuts.push(cut);
+            top = cut;
+        }
+        cuts.push(height);
+
+        let page_count = cuts.len() - 1;
+        if page_count > self.max_pages {
+            return Err(ApiError::InvalidParameter {
+                message: format!(
+                    "The receipt needs {} pages but at most {} are allowed, try more columns",
+                    page_count, self.max_pages
+                ),
+                sensitive_message: None,
+            });
+        }
+
+        Ok(cuts
+            .windows(2)
+            .map(|c| image.crop_imm(0, c[0], image.width(), c[1] - c[0]))
+            .collect())
+    }
+}
+
+/// Encodes every page and packs them, numbered in order, into a ZIP file.
+/// The images are already compressed, so they are stored as is.
+pub fn archive(
+    pages: &[DynamicImage],
+    format: &OutputFormat,
+    name: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for (index, page) in pages.iter().enumerate() {
+        zip.start_file(
+            format!("{}-{}.{}", name, index + 1, format.extension()),
+            options,
+        )?;
+        zip.write_all(&format.encode(page)?)?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::tests::receipt;
+
+    /// Pages of the 100 pixel wide test receipts are 200 rows high.
+    const PRESET: PagePreset = PagePreset {
+        max_pages: 4,
+        max_aspect_ratio: 2.0,
+        max_pixels: 1_000_000,
+    };
+
+    #[test]
+    fn ends_pages_between_rows() {
+        let pages = PRESET.split(&receipt(&[90, 90, 90, 90])).unwrap();
+
+        assert_eq!(
+            pages.iter().map(|p| p.height()).collect::<Vec<_>>(),
+            [195, 195]
+        );
+    }
+
+    #[test]
+    fn rejects_a_row_taller_than_a_page() {
+        let result = PRESET.split(&receipt(&[90, 250, 90]));
+
+        assert!(matches!(result, Err(ApiError::InvalidParameter { .. })));
+    }
+}
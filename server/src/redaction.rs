@@ -0,0 +1,144 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+use crate::config::OcrConfig;
+use crate::error::ApiError;
+use crate::ocr;
+
+const MAX_REGIONS: usize = 32;
+const FILL_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+/// Blurred regions are shrunk by this factor and scaled back up, which
+/// leaves no readable text behind, unlike a Gaussian blur of small text.
+const BLUR_BLOCK_SIZE: u32 = 16;
+const BLUR_SIGMA: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RedactionStyle {
+    #[default]
+    Fill,
+    Blur,
+}
+
+/// A rectangle of the stitched receipt, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Parses `x,y,width,height`.
+    fn parse(value: &str) -> Option<Self> {
+        let mut values = value.split(',').map(|v| v.trim().parse::<u32>());
+        let region = Self {
+            x: values.next()?.ok()?,
+            y: values.next()?.ok()?,
+            width: values.next()?.ok()?,
+            height: values.next()?.ok()?,
+        };
+
+        values.next().is_none().then_some(region)
+    }
+}
+
+/// Hides parts of the stitched receipt, such as the trainer name or ID.
+#[derive(Debug, Clone)]
+pub struct Redaction {
+    pub style: RedactionStyle,
+    pub regions: Vec<Region>,
+    /// Set to also find the trainer name, ID and friend code with OCR.
+    pub detect_personal_info: Option<OcrConfig>,
+}
+
+impl Redaction {
+    /// Parses the `redaction_style`, `redact_regions[]` and
+    /// `redact_personal_info` form fields. Returns `None` if there is nothing
+    /// to redact.
+    pub fn from_form(
+        style: Option<&str>,
+        regions: &[String],
+        personal_info: bool,
+        ocr_config: &OcrConfig,
+    ) -> Result<Option<Self>, ApiError> {
+        let style = match style {
+            None | Some("fill") => RedactionStyle::Fill,
+            Some("blur") => RedactionStyle::Blur,
+            Some(style) => {
+                return Err(ApiError::InvalidParameter {
+                    message: "Unsupported redaction style".to_string(),
+                    sensitive_message: Some(format!("Redaction style {} is not supported", style)),
+                })
+            }
+        };
+
+        if regions.len() > MAX_REGIONS {
+            return Err(ApiError::InvalidParameter {
+                message: format!("At most {} regions can be redacted", MAX_REGIONS),
+                sensitive_message: None,
+            });
+        }
+
+        let regions = regions
+            .iter()
+            .map(|r| {
+                Region::parse(r).ok_or_else(|| ApiError::InvalidParameter {
+                    message: "Redacted regions must be given as x,y,width,height".to_string(),
+                    sensitive_message: Some(format!("Invalid region {}", r)),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((!regions.is_empty() || personal_info).then(|| Self {
+            style,
+            regions,
+            detect_personal_info: personal_info.then(|| ocr_config.clone()),
+        }))
+    }
+
+    /// Redacts every region, and the personal info found if asked to,
+    /// clipped to the image.
+    pub fn apply(&self, image: &mut DynamicImage) -> Result<(), ApiError> {
+        let (image_width, image_height) = image.dimensions();
+        let detected = match &self.detect_personal_info {
+            Some(config) => ocr::find_personal_info(image, config)?,
+            None => Vec::new(),
+        };
+
+        for region in self.regions.iter().chain(&detected) {
+            if region.x >= image_width || region.y >= image_height {
+                continue;
+            }
+            let width = region.width.min(image_width - region.x);
+            let height = region.height.min(image_height - region.y);
+            if width == 0 || height == 0 {
+                continue;
+            }
+
+            match self.style {
+                RedactionStyle::Fill => {
+                    for y in region.y..region.y + height {
+                        for x in region.x..region.x + width {
+                            image.put_pixel(x, y, FILL_COLOR);
+                        }
+                    }
+                }
+                RedactionStyle::Blur => {
+                    let blurred = image
+                        .crop_imm(region.x, region.y, width, height)
+                        .resize_exact(
+                            (width / BLUR_BLOCK_SIZE).max(1),
+                            (height / BLUR_BLOCK_SIZE).max(1),
+                            FilterType::Triangle,
+                        )
+                        .resize_exact(width, height, FilterType::Nearest)
+                        .blur(BLUR_SIGMA);
+                    imageops::replace(image, &blurred, region.x as i64, region.y as i64);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
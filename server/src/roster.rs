@@ -0,0 +1,164 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+use rusttype::Scale;
+
+use crate::caption;
+use crate::error::ApiError;
+use crate::layout;
+
+pub const MAX_RUNNERS: usize = 9;
+const MAX_COLUMNS: u32 = 5;
+const DEFAULT_COLUMNS: u32 = 3;
+const MAX_TITLE_LENGTH: usize = 100;
+const MAX_LABEL_LENGTH: usize = 50;
+const DARK_TEXT_COLOR: Rgba<u8> = Rgba([32, 32, 32, 255]);
+const LIGHT_TEXT_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LINE_SPACING: f32 = 1.6;
+
+/// A team sheet: one receipt per runner in a labeled grid under a title.
+#[derive(Debug, Clone)]
+pub struct Roster {
+    pub title: Option<String>,
+    /// Label of each runner. Runners without one are numbered.
+    pub labels: Vec<String>,
+    /// `None` fits up to three runners in a row.
+    pub columns: Option<u32>,
+    /// Space between receipts and around the edges, in pixels.
+    pub gutter: u32,
+    pub background: Rgba<u8>,
+}
+
+impl Roster {
+    /// Parses the `roster_title`, `roster_labels[]` and `roster_columns` form
+    /// fields, and the `column_gutter` and `column_background` fields shared
+    /// with the column layout.
+    pub fn from_form(
+        title: Option<String>,
+        labels: Vec<String>,
+        columns: Option<u32>,
+        gutter: Option<u32>,
+        background: Option<&str>,
+    ) -> Result<Self, ApiError> {
+        if labels.len() > MAX_RUNNERS {
+            return Err(ApiError::InvalidParameter {
+                message: format!("At most {} roster labels can be given", MAX_RUNNERS),
+                sensitive_message: None,
+            });
+        }
+
+        if let Some(columns) = columns.filter(|c| !(1..=MAX_COLUMNS).contains(c)) {
+            return Err(ApiError::InvalidParameter {
+                message: format!("roster_columns must be between 1 and {}", MAX_COLUMNS),
+                sensitive_message: Some(format!("Invalid roster_columns {}", columns)),
+            });
+        }
+
+        Ok(Self {
+            title: caption::non_empty("roster_title", title, MAX_TITLE_LENGTH)?,
+            labels: labels
+                .into_iter()
+                .map(|l| {
+                    caption::non_empty("roster_labels[]", Some(l), MAX_LABEL_LENGTH)
+                        .map(Option::unwrap_or_default)
+                })
+                .collect::<Result<_, _>>()?,
+            columns,
+            gutter: layout::parse_gutter(gutter)?,
+            background: layout::parse_background(background)?,
+        })
+    }
+
+    /// Shrinks the receipts to the narrowest one and lays them out in rows,
+    /// each under its label, with the title above them all.
+    pub fn compose(&self, receipts: &[DynamicImage]) -> DynamicImage {
+        let width = receipts.iter().map(|r| r.width()).min().unwrap_or(0);
+        let receipts = receipts
+            .iter()
+            .map(|r| {
+                if r.width() == width {
+                    r.to_rgba8()
+                } else {
+                    let height = (r.height() as u64 * width as u64 / r.width() as u64) as u32;
+                    r.resize_exact(width, height, FilterType::Lanczos3)
+                        .to_rgba8()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let count = receipts.len() as u32;
+        let columns = self
+            .columns
+            .unwrap_or(DEFAULT_COLUMNS)
+            .clamp(1, count.max(1));
+        let rows = (count + columns - 1) / columns;
+
+        let (regular, bold) = (caption::regular_font(), caption::bold_font());
+        let label_size = (width as f32 / 20.0).clamp(16.0, 64.0);
+        let title_size = label_size * 1.5;
+        let label_height = (label_size * LINE_SPACING).ceil() as u32;
+        let title_height = self.title.as_ref().map_or(0, |_| {
+            (title_size * LINE_SPACING).ceil() as u32 + self.gutter
+        });
+        let cell_height = label_height + receipts.iter().map(|r| r.height()).max().unwrap_or(0);
+
+        let canvas_width = columns * width + (columns + 1) * self.gutter;
+        let mut canvas = RgbaImage::from_pixel(
+            canvas_width,
+            title_height + rows * cell_height + (rows + 1) * self.gutter,
+            self.background,
+        );
+        let text_color = text_color(self.background);
+
+        if let Some(title) = &self.title {
+            let scale = Scale::uniform(title_size);
+            let x = (canvas_width as f32 - caption::text_advance(bold, scale, title)) / 2.0;
+            caption::draw_text(
+                &mut canvas,
+                bold,
+                scale,
+                x.max(0.0),
+                self.gutter as f32,
+                text_color,
+                1.0,
+                title,
+            );
+        }
+
+        let scale = Scale::uniform(label_size);
+        for (index, receipt) in receipts.iter().enumerate() {
+            let (column, row) = (index as u32 % columns, index as u32 / columns);
+            let x = self.gutter + column * (width + self.gutter);
+            let y = title_height + self.gutter + row * (cell_height + self.gutter);
+
+            let label = match self.labels.get(index).filter(|l| !l.is_empty()) {
+                Some(label) => label.clone(),
+                None => format!("#{}", index + 1),
+            };
+            caption::draw_text(
+                &mut canvas,
+                regular,
+                scale,
+                x as f32,
+                y as f32,
+                text_color,
+                1.0,
+                &label,
+            );
+            imageops::overlay(&mut canvas, receipt, x as i64, (y + label_height) as i64);
+        }
+
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Dark text on light backgrounds and light text on dark ones.
+fn text_color(background: Rgba<u8>) -> Rgba<u8> {
+    let [r, g, b, _] = background.0;
+    let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+
+    if luma > 128.0 {
+        DARK_TEXT_COLOR
+    } else {
+        LIGHT_TEXT_COLOR
+    }
+}
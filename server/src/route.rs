@@ -11,5 +11,30 @@ pub async fn not_found(request: HttpRequest) -> impl Responder {
 }
 
 pub fn receipts(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::scope("/receipts").service(controller::receipt::insert));
+    cfg.service(
+        web::scope("/receipts")
+            .service(controller::receipt::insert)
+            .service(controller::receipt::insert_video)
+            .service(controller::receipt::analyze)
+            .service(controller::receipt::order)
+            .service(controller::receipt::recognize)
+            .service(controller::receipt::compare)
+            .service(controller::receipt::insert_roster)
+            .service(controller::share::insert)
+            .service(controller::receipt_job::insert)
+            .service(controller::receipt_job::show)
+            .service(controller::receipt_job::show_image),
+    );
+}
+
+pub fn shares(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/r")
+            .service(controller::share::show)
+            .service(controller::share::delete),
+    );
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/config").service(controller::config::show));
 }
@@ -0,0 +1,948 @@
+use std::borrow::Borrow;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, GrayImage, Rgb, RgbImage};
+use log::info;
+use serde::Serialize;
+
+use crate::detail::{self, StitchOptions};
+use crate::error::ApiError;
+
+/// Width screenshots are shrunk to before their rows are compared.
+const PROFILE_WIDTH: u32 = 32;
+/// Mean luminance difference under which two rows count as the same.
+const MAX_ROW_DIFFERENCE: f32 = 4.0;
+/// Consecutive screenshots must have at least this share of their scroll
+/// area in common.
+const MIN_OVERLAP_RATIO: f32 = 0.05;
+/// Rows at the top of each possible overlap compared to shortlist scroll
+/// offsets, so that only the shortlisted ones are compared in full.
+const SEARCH_WINDOW: u32 = 48;
+
+/// Mean luminance difference between a dialog and the dimmed screen around
+/// it.
+const MIN_PANEL_CONTRAST: f32 = 40.0;
+/// Channel spread over which a pixel counts as the colour of a title bar.
+const MIN_TITLE_SATURATION: u8 = 60;
+/// Share of the pixels at either end of a title bar that must be its colour.
+const MIN_TITLE_COVERAGE: f32 = 0.8;
+/// How far apart the starts of consecutive characters of a title are, in
+/// units of their height.
+const CHARACTER_ADVANCE: f32 = 1.1;
+/// Luminance jump between neighbouring pixels at the edge of the close
+/// button.
+const MIN_BUTTON_EDGE: u8 = 40;
+
+/// In-game screens that can be stitched, chosen by the `screen_type` form
+/// field.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScreenType {
+    /// ウマ娘詳細, stitched by `HorseGirlFullDetailImage`.
+    #[default]
+    HorseGirlDetail,
+    /// Detects the screen from the first screenshot.
+    Auto,
+    /// サポートカード詳細.
+    SupportCardDetail,
+    /// 継承 (factor) lists.
+    FactorList,
+    /// レース履歴.
+    RaceHistory,
+    /// ルームマッチ entries.
+    RoomMatchEntries,
+}
+
+/// How a screen is framed around the part that scrolls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frame {
+    /// A panel over the dimmed screen, with a title bar on top and a close
+    /// button under the scroll area. The close button is kept unless
+    /// `trim_close_button` is set.
+    Dialog,
+    /// The whole screen, with a header on top and the game's menu at the
+    /// bottom. The menu is always dropped.
+    FullScreen,
+}
+
+/// What sets a screen apart from the others.
+#[derive(Debug, Clone, Copy)]
+struct ScreenProfile {
+    frame: Frame,
+    /// Characters in the title, which tells apart screens framed the same
+    /// way.
+    title_length: usize,
+}
+
+/// Where the parts of a screen are in a screenshot, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Layout {
+    /// Columns of the panel, the whole width unless the screen is a dialog.
+    pub left: u32,
+    pub right: u32,
+    /// Top of the title bar or header.
+    pub top: u32,
+    /// Bottom of the title bar or header, where scrolling can start.
+    pub title_bottom: u32,
+    /// Top of the space around the close button, if one was found.
+    pub footer_top: Option<u32>,
+    /// Bottom of the panel.
+    pub bottom: u32,
+    /// Approximate number of characters in the title.
+    pub title_length: usize,
+}
+
+impl ScreenType {
+    /// Screen types with a profile of their own, in the order detection
+    /// prefers them.
+    const PROFILED: [ScreenType; 5] = [
+        Self::HorseGirlDetail,
+        Self::SupportCardDetail,
+        Self::FactorList,
+        Self::RaceHistory,
+        Self::RoomMatchEntries,
+    ];
+
+    pub fn from_form(name: &str) -> Result<Self, ApiError> {
+        match name {
+            "horse_girl_detail" => Ok(Self::HorseGirlDetail),
+            "auto" => Ok(Self::Auto),
+            "support_card_detail" => Ok(Self::SupportCardDetail),
+            "factor_list" => Ok(Self::FactorList),
+            "race_history" => Ok(Self::RaceHistory),
+            "room_match_entries" => Ok(Self::RoomMatchEntries),
+            _ => Err(ApiError::InvalidParameter {
+                message: "Unsupported screen type".to_string(),
+                sensitive_message: Some(format!("Screen type {} is not supported", name)),
+            }),
+        }
+    }
+
+    /// Whether screenshots are only ever stitched by
+    /// `HorseGirlFullDetailImage`, which the debug overlay, analysis and
+    /// automatic ordering rely on.
+    pub fn is_horse_girl_detail(&self) -> bool {
+        *self == Self::HorseGirlDetail
+    }
+
+    fn profile(&self) -> ScreenProfile {
+        match self {
+            // ウマ娘詳細
+            Self::HorseGirlDetail | Self::Auto => ScreenProfile {
+                frame: Frame::Dialog,
+                title_length: 5,
+            },
+            // サポートカード詳細
+            Self::SupportCardDetail => ScreenProfile {
+                frame: Frame::Dialog,
+                title_length: 9,
+            },
+            // 継承
+            Self::FactorList => ScreenProfile {
+                frame: Frame::Dialog,
+                title_length: 2,
+            },
+            // レース履歴
+            Self::RaceHistory => ScreenProfile {
+                frame: Frame::FullScreen,
+                title_length: 5,
+            },
+            // ルームマッチ出走表
+            Self::RoomMatchEntries => ScreenProfile {
+                frame: Frame::FullScreen,
+                title_length: 9,
+            },
+        }
+    }
+
+    /// Tells which screen a screenshot shows by how it is framed and how
+    /// long its title is, or `None` if it looks like none of them.
+    pub fn detect(image: &DynamicImage) -> Option<Self> {
+        let image = image.to_rgb8();
+        let (frame, layout) = match find_dialog(&image) {
+            Some(layout) => (Frame::Dialog, layout),
+            None => (Frame::FullScreen, find_full_screen(&image)?),
+        };
+        if layout.title_length == 0 {
+            return None;
+        }
+
+        Self::PROFILED
+            .iter()
+            .copied()
+            .filter(|t| t.profile().frame == frame)
+            .map(|t| (t, t.profile().title_length.abs_diff(layout.title_length)))
+            .filter(|(_, difference)| *difference <= 1)
+            .min_by_key(|(_, difference)| *difference)
+            .map(|(t, _)| t)
+    }
+
+    /// Finds the parts of this screen in a screenshot, or `None` if it is
+    /// not framed like this screen.
+    pub fn find_layout(&self, image: &DynamicImage) -> Option<Layout> {
+        let image = image.to_rgb8();
+        match self.profile().frame {
+            Frame::Dialog => find_dialog(&image),
+            Frame::FullScreen => find_full_screen(&image),
+        }
+    }
+}
+
+/// How the scroll area of a screenshot lines up with another one above it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ScrollMatch {
+    /// Rows the scroll area moved up by, at the scale screenshots are
+    /// stitched at.
+    pub offset: u32,
+    /// Rows both screenshots show.
+    pub overlap: u32,
+    /// Mean luminance difference of the rows both screenshots show, from 0
+    /// for a perfect match to 255.
+    pub difference: f32,
+}
+
+/// Row profiles of the scroll areas of screenshots of one screen, for
+/// comparing any pair of them without stitching.
+pub struct ScrollProfiles {
+    profiles: Vec<GrayImage>,
+    top: u32,
+    bottom: u32,
+}
+
+impl ScrollProfiles {
+    /// Shrinks the screenshots as stitching would and cuts out their scroll
+    /// areas: the rows between the title and the close button of the layout
+    /// found in the first screenshot, less any rows at either end that are
+    /// the same in every screenshot. Returns `None` if no layout is found or
+    /// the screenshots are not all the same size.
+    pub fn new<I: Borrow<DynamicImage>>(images: &[I], options: &StitchOptions) -> Option<Self> {
+        let images = images
+            .iter()
+            .map(|i| shrink(i.borrow(), options.scaling_threshold_pixels))
+            .collect::<Vec<_>>();
+        let first = images.first()?;
+        if images.iter().any(|i| i.dimensions() != first.dimensions()) {
+            return None;
+        }
+        let layout = options.screen_type.find_layout(first)?;
+
+        let (width, height) = (layout.right - layout.left, layout.bottom - layout.top);
+        let profiles = images
+            .iter()
+            .map(|i| row_profile(&i.crop_imm(layout.left, layout.top, width, height)))
+            .collect::<Vec<_>>();
+        let (top, bottom) = scroll_area(&profiles);
+        let top = top.max(layout.title_bottom - layout.top);
+        let bottom = bottom
+            .min(layout.footer_top.map_or(height, |y| y - layout.top))
+            .max(top);
+
+        Some(Self {
+            profiles,
+            top,
+            bottom,
+        })
+    }
+
+    /// Compares the scroll area of screenshot `lower` with that of `upper`,
+    /// taking `lower` to have scrolled further down. Returns `None` if they
+    /// do not overlap enough to tell.
+    pub fn compare(&self, upper: usize, lower: usize) -> Option<ScrollMatch> {
+        let (offset, difference) = scroll_offset(
+            &self.profiles[upper],
+            &self.profiles[lower],
+            self.top,
+            self.bottom,
+        )?;
+
+        Some(ScrollMatch {
+            offset,
+            overlap: self.bottom - self.top - offset,
+            difference,
+        })
+    }
+}
+
+/// Stitches decoded screenshots, in order, of the screen given by
+/// `options.screen_type`.
+pub fn stitch(images: &[DynamicImage], options: &StitchOptions) -> Result<DynamicImage, ApiError> {
+    match options.screen_type {
+        ScreenType::HorseGirlDetail => Ok(detail::stitch(images, options)?.convert_to_image()?),
+        ScreenType::Auto => {
+            let detected = images.first().and_then(ScreenType::detect);
+            info!("Detected screen type {:?}", detected);
+            match detected {
+                Some(ScreenType::HorseGirlDetail) | None => {
+                    match detail::stitch(images, options).and_then(|d| Ok(d.convert_to_image()?)) {
+                        Ok(image) => Ok(image),
+                        Err(e) => {
+                            info!(
+                                "Not a horse girl detail screen, stitching the scroll area: {}",
+                                e
+                            );
+                            stitch_scroll_area(images, options, ScreenType::HorseGirlDetail)
+                        }
+                    }
+                }
+                Some(screen_type) => stitch_scroll_area(images, options, screen_type),
+            }
+        }
+        screen_type => stitch_scroll_area(images, options, screen_type),
+    }
+}
+
+/// Stitches screenshots of a screen by the part that scrolls between them.
+///
+/// The screen's [`Layout`] is found in the first screenshot, and everything
+/// outside its panel is cut off. Between the title and the close button,
+/// rows that stay the same in every screenshot above and below the part that
+/// changes belong to the header and the footer. The header is kept unless
+/// `trim_title` is set, and the footer as described by [`Frame`]. The scroll
+/// areas are joined where consecutive screenshots overlap.
+fn stitch_scroll_area(
+    images: &[DynamicImage],
+    options: &StitchOptions,
+    screen_type: ScreenType,
+) -> Result<DynamicImage, ApiError> {
+    let profile = screen_type.profile();
+    let images = images
+        .iter()
+        .map(|i| shrink(i, options.scaling_threshold_pixels))
+        .collect::<Vec<_>>();
+
+    let Some(first) = images.first() else {
+        return Err(ApiError::InvalidParameter {
+            message: "No images were uploaded".to_string(),
+            sensitive_message: None,
+        });
+    };
+    let (width, height) = first.dimensions();
+    if let Some(index) = images
+        .iter()
+        .position(|i| i.dimensions() != (width, height))
+    {
+        return Err(ApiError::InvalidParameter {
+            message: format!(
+                "images[{}]: screenshots of this screen must all be the same size",
+                index
+            ),
+            sensitive_message: None,
+        });
+    }
+
+    let layout = screen_type.find_layout(first).unwrap_or_else(|| {
+        info!(
+            "No {:?} layout found, stitching the whole screenshots",
+            screen_type
+        );
+        Layout {
+            left: 0,
+            right: width,
+            top: 0,
+            title_bottom: 0,
+            footer_top: None,
+            bottom: height,
+            title_length: 0,
+        }
+    });
+    info!("Layout found: {:?}", layout);
+
+    let images = images
+        .iter()
+        .map(|i| {
+            i.crop_imm(
+                layout.left,
+                layout.top,
+                layout.right - layout.left,
+                layout.bottom - layout.top,
+            )
+        })
+        .collect::<Vec<_>>();
+    let (width, height) = (layout.right - layout.left, layout.bottom - layout.top);
+    let title_bottom = layout.title_bottom - layout.top;
+    let footer_top = layout.footer_top.map_or(height, |y| y - layout.top);
+
+    let profiles = images.iter().map(row_profile).collect::<Vec<_>>();
+    let (top, bottom) = scroll_area(&profiles);
+    let top = top.max(title_bottom);
+    let bottom = bottom.min(footer_top).max(top);
+    info!("Scroll area found between rows {} and {}", top, bottom);
+
+    let mut pieces = Vec::new();
+    if !options.trim_title {
+        pieces.push((0, 0, top));
+    }
+    pieces.push((0, top, bottom));
+    for index in 1..images.len() {
+        let (offset, _) = scroll_offset(&profiles[index - 1], &profiles[index], top, bottom)
+            .ok_or_else(|| ApiError::ImageGenerateError {
+                message: format!("images[{}] does not overlap the previous screenshot", index),
+            })?;
+        pieces.push((index, bottom - offset, bottom));
+    }
+    if profile.frame == Frame::Dialog && !options.trim_close_button {
+        pieces.push((images.len() - 1, bottom, height));
+    }
+
+    let stitched_height = pieces.iter().map(|(_, from, to)| to - from).sum::<u32>();
+    let mut stitched = DynamicImage::new_rgba8(width, stitched_height);
+    let mut y = 0;
+    for (index, from, to) in pieces {
+        if to > from {
+            let piece = images[index].view(0, from, width, to - from).to_image();
+            image::imageops::replace(&mut stitched, &piece, 0, y as i64);
+            y += to - from;
+        }
+    }
+
+    Ok(stitched)
+}
+
+/// Finds a dialog: a panel in the middle of the screenshot, brighter than
+/// the dimmed screen on both sides of it, with a title bar at its top.
+fn find_dialog(image: &RgbImage) -> Option<Layout> {
+    let (width, height) = image.dimensions();
+    if width < 16 || height < 16 {
+        return None;
+    }
+
+    let column_means = (0..width)
+        .map(|x| {
+            (0..height)
+                .map(|y| luma(image.get_pixel(x, y)))
+                .sum::<f32>()
+                / height as f32
+        })
+        .collect::<Vec<_>>();
+    let left =
+        (0..width).find(|&x| column_means[x as usize] > column_means[0] + MIN_PANEL_CONTRAST)?;
+    let right = (0..width).rev().find(|&x| {
+        column_means[x as usize] > column_means[width as usize - 1] + MIN_PANEL_CONTRAST
+    })? + 1;
+    let margin = width - right;
+    if left < width / 50
+        || margin < width / 50
+        || left > width / 4
+        || margin > width / 4
+        || left.abs_diff(margin) > width / 50
+    {
+        return None;
+    }
+
+    let dimmed = (column_means[0] + column_means[width as usize - 1]) / 2.0;
+    let is_panel = |y: u32| {
+        (left..right)
+            .map(|x| luma(image.get_pixel(x, y)))
+            .sum::<f32>()
+            / (right - left) as f32
+            > dimmed + MIN_PANEL_CONTRAST
+    };
+    let top = (0..height).find(|&y| is_panel(y))?;
+    let bottom = (top..height).rev().find(|&y| is_panel(y))? + 1;
+
+    let (title_top, title_bottom) = find_title_bar(image, left, right, top, bottom)?;
+    let footer_top = find_close_button(image, left, right, title_bottom, bottom);
+
+    Some(Layout {
+        left,
+        right,
+        top: title_top,
+        title_bottom,
+        footer_top,
+        bottom,
+        title_length: title_length(image, left, right, title_top, title_bottom),
+    })
+}
+
+/// Finds a full screen: a header across the whole width at the top of the
+/// screenshot.
+fn find_full_screen(image: &RgbImage) -> Option<Layout> {
+    let (width, height) = image.dimensions();
+    if width < 16 || height < 16 {
+        return None;
+    }
+
+    let (top, title_bottom) = find_title_bar(image, 0, width, 0, height)?;
+
+    Some(Layout {
+        left: 0,
+        right: width,
+        top,
+        title_bottom,
+        footer_top: None,
+        bottom: height,
+        title_length: title_length(image, 0, width, top, title_bottom),
+    })
+}
+
+/// The rows of a title bar starting near `top`: a band of one strong colour
+/// across the columns, with the title in the middle. Only the ends of each
+/// row are looked at, so a long title does not hide the bar.
+fn find_title_bar(
+    image: &RgbImage,
+    left: u32,
+    right: u32,
+    top: u32,
+    bottom: u32,
+) -> Option<(u32, u32)> {
+    let end = ((right - left) / 10).max(1);
+    let is_title_bar = |y: u32| {
+        let coloured = (left..left + end)
+            .chain(right - end..right)
+            .filter(|&x| saturation(image.get_pixel(x, y)) >= MIN_TITLE_SATURATION)
+            .count();
+        coloured as f32 >= (2 * end) as f32 * MIN_TITLE_COVERAGE
+    };
+
+    let search_end = (top + image.height() / 20).min(bottom);
+    let title_top = (top..search_end).find(|&y| is_title_bar(y))?;
+    let title_bottom = (title_top..bottom)
+        .find(|&y| !is_title_bar(y))
+        .unwrap_or(bottom);
+    if title_bottom - title_top < (image.height() / 100).max(4) {
+        return None;
+    }
+
+    Some((title_top, title_bottom))
+}
+
+/// Roughly how many characters are written in white on a title bar, from
+/// the width of the title over its height.
+fn title_length(image: &RgbImage, left: u32, right: u32, top: u32, bottom: u32) -> usize {
+    let is_text = |x: u32, y: u32| {
+        let pixel = image.get_pixel(x, y);
+        luma(pixel) >= 200.0 && saturation(pixel) < 40
+    };
+    let columns = (left..right)
+        .filter(|&x| (top..bottom).any(|y| is_text(x, y)))
+        .collect::<Vec<_>>();
+    let rows = (top..bottom)
+        .filter(|&y| (left..right).any(|x| is_text(x, y)))
+        .collect::<Vec<_>>();
+    let (Some(first_column), Some(last_column), Some(first_row), Some(last_row)) =
+        (columns.first(), columns.last(), rows.first(), rows.last())
+    else {
+        return 0;
+    };
+
+    let text_width = (last_column - first_column + 1) as f32;
+    let text_height = (last_row - first_row + 1) as f32;
+    ((text_width + (CHARACTER_ADVANCE - 1.0) * text_height) / (CHARACTER_ADVANCE * text_height))
+        .round() as usize
+}
+
+/// The top of the space around a dialog's close button, which sits in the
+/// middle of the bottom quarter of the panel. Rows of the button have sharp
+/// edges at the same distance on both sides of the middle; the space above
+/// the button is taken to be as tall as the space below it.
+fn find_close_button(
+    image: &RgbImage,
+    left: u32,
+    right: u32,
+    top: u32,
+    bottom: u32,
+) -> Option<u32> {
+    let panel_width = right - left;
+    let middle = left + panel_width / 2;
+    let (inner_left, inner_right) = (left + panel_width / 10, right - panel_width / 10);
+    let is_button = |y: u32| {
+        let edges = (inner_left..inner_right - 1)
+            .filter(|&x| {
+                (luma(image.get_pixel(x, y)) - luma(image.get_pixel(x + 1, y))).abs()
+                    >= MIN_BUTTON_EDGE as f32
+            })
+            .collect::<Vec<_>>();
+        match (edges.first(), edges.last()) {
+            (Some(&first), Some(&last)) if last > first => {
+                let centre = first + (last - first) / 2;
+                centre.abs_diff(middle) <= panel_width / 20
+            }
+            _ => false,
+        }
+    };
+
+    let search_top = bottom - (bottom - top) / 4;
+    let button_bottom = (search_top..bottom).rev().find(|&y| is_button(y))? + 1;
+    let button_top = (search_top..button_bottom)
+        .rev()
+        .find(|&y| !is_button(y))
+        .map_or(search_top, |y| y + 1);
+
+    Some(button_top.saturating_sub(bottom - button_bottom).max(top))
+}
+
+fn luma(pixel: &Rgb<u8>) -> f32 {
+    let [r, g, b] = pixel.0;
+    0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32
+}
+
+fn saturation(pixel: &Rgb<u8>) -> u8 {
+    let [r, g, b] = pixel.0;
+    r.max(g).max(b) - r.min(g).min(b)
+}
+
+/// Shrinks a screenshot to at most `max_pixels`, if set.
+fn shrink(image: &DynamicImage, max_pixels: Option<u32>) -> DynamicImage {
+    let pixels = image.width() as u64 * image.height() as u64;
+    match max_pixels {
+        Some(max_pixels) if pixels > max_pixels as u64 => {
+            let scale = (max_pixels as f64 / pixels as f64).sqrt();
+            image.resize_exact(
+                ((image.width() as f64 * scale) as u32).max(1),
+                ((image.height() as f64 * scale) as u32).max(1),
+                FilterType::Lanczos3,
+            )
+        }
+        _ => image.clone(),
+    }
+}
+
+fn row_profile(image: &DynamicImage) -> GrayImage {
+    image
+        .resize_exact(PROFILE_WIDTH, image.height(), FilterType::Triangle)
+        .to_luma8()
+}
+
+fn row_difference(a: &GrayImage, a_row: u32, b: &GrayImage, b_row: u32) -> f32 {
+    (0..PROFILE_WIDTH)
+        .map(|x| a.get_pixel(x, a_row)[0].abs_diff(b.get_pixel(x, b_row)[0]) as f32)
+        .sum::<f32>()
+        / PROFILE_WIDTH as f32
+}
+
+/// The first and last rows, exclusive, of the part that changes between
+/// screenshots. A single screenshot is all scroll area.
+fn scroll_area(profiles: &[GrayImage]) -> (u32, u32) {
+    let height = profiles[0].height();
+    if profiles.len() < 2 {
+        return (0, height);
+    }
+
+    let is_fixed = |y: u32| {
+        profiles
+            .windows(2)
+            .all(|p| row_difference(&p[0], y, &p[1], y) <= MAX_ROW_DIFFERENCE)
+    };
+
+    let top = (0..height).find(|&y| !is_fixed(y)).unwrap_or(height);
+    let bottom = (top..height)
+        .rev()
+        .find(|&y| !is_fixed(y))
+        .map_or(top, |y| y + 1);
+
+    (top, bottom)
+}
+
+/// How many rows the scroll area of `current` has scrolled up from
+/// `previous`, and the mean difference of the rows they share, or `None` if
+/// they do not overlap enough to tell.
+///
+/// Every offset that leaves enough overlap is first checked on the top
+/// [`SEARCH_WINDOW`] rows of the overlap only, which keeps the search linear
+/// in the height of the scroll area for content that does not repeat.
+fn scroll_offset(
+    previous: &GrayImage,
+    current: &GrayImage,
+    top: u32,
+    bottom: u32,
+) -> Option<(u32, f32)> {
+    let height = bottom - top;
+    if height == 0 {
+        return Some((0, 0.0));
+    }
+    let min_overlap = ((height as f32 * MIN_OVERLAP_RATIO) as u32).max(1);
+
+    let mean_difference = |offset: u32, rows: u32| {
+        (0..rows)
+            .map(|y| row_difference(previous, top + offset + y, current, top + y))
+            .sum::<f32>()
+            / rows as f32
+    };
+
+    (0..=height.saturating_sub(min_overlap))
+        .filter(|&offset| {
+            mean_difference(offset, (height - offset).min(SEARCH_WINDOW)) <= MAX_ROW_DIFFERENCE
+        })
+        .map(|offset| (offset, mean_difference(offset, height - offset)))
+        .filter(|(_, difference)| *difference <= MAX_ROW_DIFFERENCE)
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 300;
+    const HEIGHT: u32 = 600;
+    const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/screen");
+
+    /// Content that scrolls: bands of slightly tinted grey, ten rows each,
+    /// in an order that does not repeat.
+    pub(crate) fn content(rows: u32) -> RgbImage {
+        RgbImage::from_fn(WIDTH, rows, |_, y| {
+            let mut hash = (y / 10) as u64 ^ 0x9e37_79b9_7f4a_7c15;
+            hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            let grey = 110 + ((hash ^ (hash >> 31)) % 140) as u8;
+            Rgb([grey, grey, grey - 10])
+        })
+    }
+
+    fn fill(image: &mut RgbImage, left: u32, top: u32, right: u32, bottom: u32, colour: Rgb<u8>) {
+        for y in top..bottom {
+            for x in left..right {
+                image.put_pixel(x, y, colour);
+            }
+        }
+    }
+
+    /// A title of square characters, centred on the screenshot.
+    fn title(image: &mut RgbImage, length: u32, top: u32) {
+        let left = WIDTH / 2 - (length * 22 - 2) / 2;
+        for index in 0..length {
+            let x = left + index * 22;
+            fill(image, x, top, x + 20, top + 20, WHITE);
+        }
+    }
+
+    fn copy_content(
+        image: &mut RgbImage,
+        content: &RgbImage,
+        columns: (u32, u32),
+        rows: (u32, u32),
+        offset: u32,
+    ) {
+        for y in rows.0..rows.1 {
+            for x in columns.0..columns.1 {
+                image.put_pixel(x, y, *content.get_pixel(x, y - rows.0 + offset));
+            }
+        }
+    }
+
+    /// A dialog over the dimmed screen: the panel spans columns 30 to 270
+    /// and rows 60 to 540, with the title bar down to row 100 and the close
+    /// button from row 470.
+    fn dialog(title_length: u32, content: &RgbImage, offset: u32) -> RgbImage {
+        let mut image = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([40, 40, 40]));
+        fill(&mut image, 30, 60, 270, 100, Rgb([60, 170, 80]));
+        title(&mut image, title_length, 70);
+        copy_content(&mut image, content, (30, 270), (100, 470), offset);
+        fill(&mut image, 30, 470, 270, 540, Rgb([235, 235, 235]));
+        fill(&mut image, 110, 485, 190, 525, Rgb([100, 100, 100]));
+        fill(&mut image, 112, 487, 188, 523, WHITE);
+        image
+    }
+
+    /// A full screen with the header down to row 50 and the menu from row
+    /// 520.
+    fn full_screen(title_length: u32, content: &RgbImage, offset: u32) -> RgbImage {
+        let mut image = RgbImage::from_pixel(WIDTH, HEIGHT, Rgb([70, 70, 70]));
+        fill(&mut image, 0, 0, WIDTH, 50, Rgb([50, 90, 200]));
+        title(&mut image, title_length, 15);
+        copy_content(&mut image, content, (0, WIDTH), (50, 520), offset);
+        for index in 0..5 {
+            fill(
+                &mut image,
+                20 + index * 60,
+                540,
+                40 + index * 60,
+                580,
+                Rgb([200, 200, 200]),
+            );
+        }
+        image
+    }
+
+    pub(crate) fn screenshot(
+        screen_type: ScreenType,
+        content: &RgbImage,
+        offset: u32,
+    ) -> DynamicImage {
+        let profile = screen_type.profile();
+        let title_length = profile.title_length as u32;
+        DynamicImage::ImageRgb8(match profile.frame {
+            Frame::Dialog => dialog(title_length, content, offset),
+            Frame::FullScreen => full_screen(title_length, content, offset),
+        })
+    }
+
+    pub(crate) fn options(screen_type: ScreenType, trim_close_button: bool) -> StitchOptions {
+        StitchOptions {
+            trim_margin: false,
+            trim_close_button,
+            trim_title: false,
+            match_threshold: 0,
+            scaling_threshold_pixels: None,
+            screen_type,
+        }
+    }
+
+    /// Stacks rows of screenshots, each given as `(image, left, right, top, bottom)`.
+    fn stack(pieces: &[(&RgbImage, u32, u32, u32, u32)]) -> RgbImage {
+        let width = pieces[0].2 - pieces[0].1;
+        let height = pieces.iter().map(|p| p.4 - p.3).sum::<u32>();
+        let mut stacked = RgbImage::new(width, height);
+        let mut y = 0;
+        for (image, left, right, top, bottom) in pieces {
+            let piece = image
+                .view(*left, *top, right - left, bottom - top)
+                .to_image();
+            image::imageops::replace(&mut stacked, &piece, 0, y as i64);
+            y += bottom - top;
+        }
+        stacked
+    }
+
+    #[test]
+    fn detects_each_screen() {
+        let content = content(800);
+        for screen_type in ScreenType::PROFILED {
+            assert_eq!(
+                ScreenType::detect(&screenshot(screen_type, &content, 0)),
+                Some(screen_type)
+            );
+        }
+    }
+
+    /// A screenshot of each screen, named after its `screen_type` form value,
+    /// and the layout expected to be found in it.
+    #[test]
+    fn detects_fixtures() {
+        for name in [
+            "horse_girl_detail",
+            "support_card_detail",
+            "factor_list",
+            "race_history",
+            "room_match_entries",
+        ] {
+            let image = image::open(format!("{}/{}.jpg", FIXTURES, name)).unwrap();
+            let expected = std::fs::read_to_string(format!("{}/{}.json", FIXTURES, name)).unwrap();
+            let screen_type = ScreenType::from_form(name).unwrap();
+
+            assert_eq!(ScreenType::detect(&image), Some(screen_type), "{}", name);
+            assert_eq!(
+                serde_json::to_value(screen_type.find_layout(&image)).unwrap(),
+                serde_json::from_str::<serde_json::Value>(&expected).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn does_not_detect_plain_screenshots() {
+        let image = DynamicImage::ImageRgb8(content(HEIGHT));
+        assert_eq!(ScreenType::detect(&image), None);
+    }
+
+    #[test]
+    fn finds_dialog_layout() {
+        let image = screenshot(ScreenType::SupportCardDetail, &content(800), 0);
+        assert_eq!(
+            ScreenType::SupportCardDetail.find_layout(&image),
+            Some(Layout {
+                left: 30,
+                right: 270,
+                top: 60,
+                title_bottom: 100,
+                footer_top: Some(470),
+                bottom: 540,
+                title_length: 9,
+            })
+        );
+        assert_eq!(ScreenType::RaceHistory.find_layout(&image), None);
+    }
+
+    #[test]
+    fn stitches_each_screen() {
+        let content = content(800);
+        for screen_type in ScreenType::PROFILED {
+            let screenshots = [0, 150, 300]
+                .iter()
+                .map(|&offset| screenshot(screen_type, &content, offset))
+                .collect::<Vec<_>>();
+            let first = screenshots[0].to_rgb8();
+
+            let expected = match screen_type.profile().frame {
+                Frame::Dialog => stack(&[
+                    (&first, 30, 270, 60, 100),
+                    (&content, 30, 270, 0, 670),
+                    (&first, 30, 270, 470, 540),
+                ]),
+                Frame::FullScreen => {
+                    stack(&[(&first, 0, WIDTH, 0, 50), (&content, 0, WIDTH, 0, 770)])
+                }
+            };
+            let stitched =
+                stitch_scroll_area(&screenshots, &options(screen_type, false), screen_type)
+                    .unwrap();
+            assert_eq!(
+                stitched.to_rgb8(),
+                expected,
+                "{:?} was not stitched as expected",
+                screen_type
+            );
+        }
+    }
+
+    #[test]
+    fn compares_scroll_areas() {
+        let content = content(800);
+        let screenshots = [100, 230, 300]
+            .iter()
+            .map(|&offset| screenshot(ScreenType::HorseGirlDetail, &content, offset))
+            .collect::<Vec<_>>();
+        let profiles =
+            ScrollProfiles::new(&screenshots, &options(ScreenType::HorseGirlDetail, false))
+                .unwrap();
+
+        assert_eq!(
+            profiles.compare(0, 1),
+            Some(ScrollMatch {
+                offset: 130,
+                overlap: 240,
+                difference: 0.0,
+            })
+        );
+        assert_eq!(profiles.compare(0, 2).map(|m| m.offset), Some(200));
+        assert_eq!(profiles.compare(2, 0), None);
+    }
+
+    #[test]
+    fn trims_the_close_button_of_a_single_dialog() {
+        let screen_type = ScreenType::FactorList;
+        let screenshot = screenshot(screen_type, &content(800), 0);
+        let first = screenshot.to_rgb8();
+
+        let kept = stitch_scroll_area(
+            std::slice::from_ref(&screenshot),
+            &options(screen_type, false),
+            screen_type,
+        )
+        .unwrap();
+        assert_eq!(kept.to_rgb8(), stack(&[(&first, 30, 270, 60, 540)]));
+
+        let trimmed =
+            stitch_scroll_area(&[screenshot], &options(screen_type, true), screen_type).unwrap();
+        assert_eq!(trimmed.to_rgb8(), stack(&[(&first, 30, 270, 60, 470)]));
+    }
+
+    #[test]
+    fn stitches_whole_screenshots_without_a_layout() {
+        let content = content(900);
+        let screenshots = [0, 200]
+            .iter()
+            .map(|&offset| {
+                DynamicImage::ImageRgb8(content.view(0, offset, WIDTH, HEIGHT).to_image())
+            })
+            .collect::<Vec<_>>();
+
+        let stitched = stitch_scroll_area(
+            &screenshots,
+            &options(ScreenType::RaceHistory, false),
+            ScreenType::RaceHistory,
+        )
+        .unwrap();
+        assert_eq!(stitched.to_rgb8(), stack(&[(&content, 0, WIDTH, 0, 800)]));
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::storage::Storage;
+
+/// A receipt stored to be shared by a permalink.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedReceipt {
+    pub id: String,
+    pub mime: String,
+    pub extension: String,
+    pub width: u32,
+    pub height: u32,
+    /// Seconds since the Unix epoch.
+    pub expires_at: i64,
+    /// SHA-256 of the delete token given to the uploader.
+    pub delete_token_hash: String,
+}
+
+impl SharedReceipt {
+    /// Describes a new receipt under a random id, returning it with the
+    /// token needed to delete it.
+    pub fn new(
+        mime: String,
+        extension: String,
+        width: u32,
+        height: u32,
+        ttl: Duration,
+    ) -> (Self, String) {
+        let delete_token = Uuid::new_v4().simple().to_string();
+        let receipt = Self {
+            id: Uuid::new_v4().simple().to_string(),
+            mime,
+            extension,
+            width,
+            height,
+            expires_at: chrono::Utc::now().timestamp() + ttl.as_secs() as i64,
+            delete_token_hash: hash_token(&delete_token),
+        };
+
+        (receipt, delete_token)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now().timestamp()
+    }
+
+    pub fn accepts_delete_token(&self, token: &str) -> bool {
+        hash_token(token) == self.delete_token_hash
+    }
+}
+
+const PREFIX: &str = "shares/";
+
+/// Shared receipts, kept in [`Storage`] as `shares/{id}.json` with the
+/// metadata next to `shares/{id}.{extension}` with the image. Every call may
+/// block.
+pub struct ShareStore {
+    storage: Arc<dyn Storage>,
+}
+
+impl ShareStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub fn put(&self, receipt: &SharedReceipt, image: &[u8]) -> anyhow::Result<()> {
+        // The metadata is written last, so a receipt is never found without
+        // its image.
+        self.storage
+            .put(&image_key(&receipt.id, &receipt.extension), image)?;
+        self.storage
+            .put(&metadata_key(&receipt.id), &serde_json::to_vec(receipt)?)
+    }
+
+    /// Returns `None` if there is no receipt with the id.
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<SharedReceipt>> {
+        self.storage
+            .get(&metadata_key(id))?
+            .map(|metadata| Ok(serde_json::from_slice(&metadata)?))
+            .transpose()
+    }
+
+    pub fn image(&self, receipt: &SharedReceipt) -> anyhow::Result<Vec<u8>> {
+        self.storage
+            .get(&image_key(&receipt.id, &receipt.extension))?
+            .ok_or_else(|| anyhow::anyhow!("Image of shared receipt {} is missing", receipt.id))
+    }
+
+    /// Deleting a receipt that does not exist is not an error.
+    pub fn delete(&self, id: &str) -> anyhow::Result<()> {
+        let Some(receipt) = self.get(id)? else {
+            return Ok(());
+        };
+
+        self.storage.delete(&metadata_key(id))?;
+        self.storage.delete(&image_key(id, &receipt.extension))
+    }
+
+    /// Ids of every stored receipt.
+    pub fn ids(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .storage
+            .list(PREFIX)?
+            .iter()
+            .filter_map(|key| key.strip_prefix(PREFIX)?.strip_suffix(".json"))
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Deletes every expired receipt, returning how many were deleted.
+    pub fn remove_expired(&self) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for id in self.ids()? {
+            if self.get(&id)?.map_or(false, |r| r.is_expired()) {
+                self.delete(&id)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+fn metadata_key(id: &str) -> String {
+    format!("{}{}.json", PREFIX, id)
+}
+
+fn image_key(id: &str, extension: &str) -> String {
+    format!("{}{}.{}", PREFIX, id, extension)
+}
+
+/// Ids are the simple form of a random UUID.
+pub fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+pub async fn sweep_expired(shares: web::Data<ShareStore>, interval: Duration) {
+    let mut interval = actix_web::rt::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let shares = shares.clone();
+        match web::block(move || shares.remove_expired()).await {
+            Ok(Ok(removed)) if removed > 0 => info!("Removed {} expired shared receipts", removed),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Failed to remove expired shared receipts: {:?}", e),
+            Err(e) => error!("Failed to remove expired shared receipts: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use log::{error, info};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::StitchingConfig;
+use crate::error::ApiError;
+
+/// Runs CPU-heavy stitching on the blocking thread pool.
+///
+/// Stitching, OCR and frame picking take seconds of CPU each, so handlers
+/// run them through [`Stitcher::run`] or [`Admission::run`] rather than on
+/// the async executor.
+///
+/// At most `workers` jobs run at the same time and at most `queue_depth`
+/// more wait for a free worker. Anything beyond that is rejected with
+/// [`ApiError::ServerBusy`] instead of stalling the async executor.
+pub struct Stitcher {
+    workers: Arc<Semaphore>,
+    admission: Arc<Semaphore>,
+    retry_after_seconds: u64,
+}
+
+/// A reserved slot in the stitching queue, obtained from [`Stitcher::admit`].
+///
+/// Also an extractor: list it before the `MultipartForm` argument of a
+/// handler and a full queue is rejected before the uploads are read.
+pub struct Admission {
+    workers: Arc<Semaphore>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Stitcher {
+    pub fn new(config: &StitchingConfig) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(config.workers)),
+            admission: Arc::new(Semaphore::new(config.workers + config.queue_depth)),
+            retry_after_seconds: config.retry_after_seconds,
+        }
+    }
+
+    pub fn admit(&self) -> Result<Admission, ApiError> {
+        let permit = self.admission.clone().try_acquire_owned().map_err(|_| {
+            info!("Stitching queue is full");
+            ApiError::ServerBusy {
+                retry_after: self.retry_after_seconds,
+            }
+        })?;
+
+        Ok(Admission {
+            workers: self.workers.clone(),
+            _permit: permit,
+        })
+    }
+
+    pub async fn run<F, R>(&self, f: F) -> Result<R, ApiError>
+    where
+        F: FnOnce() -> Result<R, ApiError> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.admit()?.run(f).await
+    }
+}
+
+impl FromRequest for Admission {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, ApiError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.app_data::<web::Data<Stitcher>>() {
+            Some(stitcher) => stitcher.admit(),
+            None => {
+                error!("Stitcher is not configured");
+                Err(ApiError::ImageGenerateError {
+                    message: "Failed to generate image".to_string(),
+                })
+            }
+        })
+    }
+}
+
+impl Admission {
+    pub async fn run<F, R>(self, f: F) -> Result<R, ApiError>
+    where
+        F: FnOnce() -> Result<R, ApiError> + Send + 'static,
+        R: Send + 'static,
+    {
+        let _worker = self.workers.clone().acquire_owned().await.map_err(|_| {
+            ApiError::ImageGenerateError {
+                message: "Failed to generate image".to_string(),
+            }
+        })?;
+
+        web::block(f).await.map_err(|e| {
+            error!("Stitching task panicked: {:?}", e);
+            ApiError::ImageGenerateError {
+                message: "Failed to generate image".to_string(),
+            }
+        })?
+    }
+}
@@ -0,0 +1,430 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use s3::creds::Credentials;
+use s3::{Bucket, Region};
+
+use crate::config::StorageConfig;
+
+/// Objects kept beyond a single request, shared by every replica of the
+/// server. Keys are `/`-separated paths such as `shares/{id}.json`. Every
+/// call may block.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()>;
+
+    /// Returns `None` if there is no object under the key.
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    /// Deleting an object that does not exist is not an error.
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+
+    /// Keys of the objects directly under `prefix`, which ends with `/`.
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+}
+
+pub fn from_config(config: &StorageConfig) -> anyhow::Result<Arc<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Local { directory } => Arc::new(LocalStorage::new(directory)?),
+        StorageConfig::S3 {
+            bucket,
+            region,
+            endpoint,
+            access_key_id,
+            secret_access_key,
+            path_style,
+        } => Arc::new(S3Storage::new(
+            bucket,
+            region,
+            endpoint.as_deref(),
+            access_key_id.as_deref(),
+            secret_access_key.as_deref(),
+            *path_style,
+        )?),
+    })
+}
+
+/// Keeps objects as files under a local directory. Only suits a single
+/// server, or several sharing the directory over a network file system.
+pub struct LocalStorage {
+    directory: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        Ok(Self { directory })
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let is_valid = key
+            .split('/')
+            .all(|c| !c.is_empty() && c != "." && c != "..");
+        anyhow::ensure!(is_valid, "Invalid storage key {:?}", key);
+
+        Ok(self.directory.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Written aside and renamed, so that an object is never read half
+        // written.
+        let partial = path.with_file_name(format!(".{}.partial", uuid::Uuid::new_v4().simple()));
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, &path)?;
+
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.path(prefix.trim_end_matches('/'))?) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut keys = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str().filter(|n| !n.starts_with('.')) {
+                keys.push(format!("{}{}", prefix, name));
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+/// Keeps objects in a bucket of S3 or a compatible service such as MinIO.
+pub struct S3Storage {
+    bucket: Bucket,
+}
+
+impl S3Storage {
+    /// Credentials not given are read from the `AWS_*` environment
+    /// variables or the instance profile, like the AWS CLI does.
+    pub fn new(
+        bucket: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        access_key_id: Option<&str>,
+        secret_access_key: Option<&str>,
+        path_style: bool,
+    ) -> anyhow::Result<Self> {
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom {
+                region: region.to_string(),
+                endpoint: endpoint.to_string(),
+            },
+            None => region.parse()?,
+        };
+        let credentials = Credentials::new(access_key_id, secret_access_key, None, None, None)
+            .context("Failed to load S3 credentials")?;
+
+        let bucket = Bucket::new(bucket, region, credentials)?;
+        let bucket = if path_style {
+            bucket.with_path_style()
+        } else {
+            bucket
+        };
+
+        Ok(Self { bucket })
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let response = self.bucket.put_object(key, bytes)?;
+        ensure_success(key, response.status_code())
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self.bucket.get_object(key)?;
+        if response.status_code() == 404 {
+            return Ok(None);
+        }
+        ensure_success(key, response.status_code())?;
+
+        Ok(Some(response.bytes().to_vec()))
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let response = self.bucket.delete_object(key)?;
+        if response.status_code() == 404 {
+            return Ok(());
+        }
+
+        ensure_success(key, response.status_code())
+    }
+
+    fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .bucket
+            .list(prefix.to_string(), Some("/".to_string()))?
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+}
+
+fn ensure_success(key: &str, status: u16) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (200..300).contains(&status),
+        "S3 responded {} for {}",
+        status,
+        key
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Exercises the contract every backend has to keep.
+    fn round_trip(storage: &dyn Storage) {
+        assert_eq!(storage.get("shares/a.json").unwrap(), None);
+
+        storage.put("shares/a.json", b"{}").unwrap();
+        storage.put("shares/a.png", b"png").unwrap();
+        storage.put("shares/nested/b.json", b"{}").unwrap();
+        storage.put("uploads/c.mp4", b"mp4").unwrap();
+
+        assert_eq!(storage.get("shares/a.png").unwrap(), Some(b"png".to_vec()));
+
+        storage.put("shares/a.png", b"replaced").unwrap();
+        assert_eq!(
+            storage.get("shares/a.png").unwrap(),
+            Some(b"replaced".to_vec())
+        );
+
+        let mut keys = storage.list("shares/").unwrap();
+        keys.sort();
+        assert_eq!(keys, ["shares/a.json", "shares/a.png"]);
+        assert_eq!(storage.list("missing/").unwrap(), Vec::<String>::new());
+
+        storage.delete("shares/a.json").unwrap();
+        storage.delete("shares/a.json").unwrap();
+        assert_eq!(storage.get("shares/a.json").unwrap(), None);
+        assert_eq!(storage.list("shares/").unwrap(), ["shares/a.png"]);
+    }
+
+    #[test]
+    fn local_storage_round_trips_objects() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let storage = LocalStorage::new(&directory).unwrap();
+
+        round_trip(&storage);
+        assert!(directory.join("shares/a.png").exists());
+        assert!(storage.put("../escaped", b"").is_err());
+        assert!(storage.get("shares//a.png").is_err());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn s3_storage_round_trips_objects() {
+        let endpoint = fake_s3::start("receipts");
+        let storage = S3Storage::new(
+            "receipts",
+            "us-east-1",
+            Some(&endpoint),
+            Some("access-key"),
+            Some("secret-key"),
+            true,
+        )
+        .unwrap();
+
+        round_trip(&storage);
+    }
+
+    /// A stand-in for MinIO that keeps objects in memory and answers the
+    /// path-style requests `S3Storage` makes, one connection at a time.
+    mod fake_s3 {
+        use std::collections::BTreeMap;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+
+        /// Returns the endpoint to send requests to.
+        pub fn start(bucket: &str) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = format!("http://{}", listener.local_addr().unwrap());
+            let bucket = bucket.to_string();
+
+            thread::spawn(move || {
+                let mut objects = BTreeMap::new();
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    if let Err(e) = handle(stream, &bucket, &mut objects) {
+                        eprintln!("Fake S3 failed: {:?}", e);
+                    }
+                }
+            });
+
+            endpoint
+        }
+
+        fn handle(
+            stream: TcpStream,
+            bucket: &str,
+            objects: &mut BTreeMap<String, Vec<u8>>,
+        ) -> std::io::Result<()> {
+            let mut reader = BufReader::new(stream.try_clone()?);
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line)?;
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let target = parts.next().unwrap_or_default().to_string();
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or_default();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body)?;
+
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            let key = path
+                .strip_prefix(&format!("/{}", bucket))
+                .map(|k| decode(k.trim_start_matches('/')));
+
+            let (status, response) = match (method.as_str(), key) {
+                (_, None) => (404, Vec::new()),
+                ("GET", Some(key)) if key.is_empty() => {
+                    let prefix = query_value(query, "prefix").unwrap_or_default();
+                    (200, list(bucket, &prefix, objects).into_bytes())
+                }
+                ("PUT", Some(key)) => {
+                    objects.insert(key, body);
+                    (200, Vec::new())
+                }
+                ("GET", Some(key)) => match objects.get(&key) {
+                    Some(object) => (200, object.clone()),
+                    None => (404, b"<Error><Code>NoSuchKey</Code></Error>".to_vec()),
+                },
+                ("DELETE", Some(key)) => {
+                    objects.remove(&key);
+                    (204, Vec::new())
+                }
+                _ => (405, Vec::new()),
+            };
+
+            let mut stream = stream;
+            write!(
+                stream,
+                "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nETag: \"fake\"\r\nConnection: close\r\n\r\n",
+                status,
+                response.len()
+            )?;
+            stream.write_all(&response)?;
+            stream.flush()
+        }
+
+        /// A `ListObjectsV2` result for the objects directly under `prefix`.
+        fn list(bucket: &str, prefix: &str, objects: &BTreeMap<String, Vec<u8>>) -> String {
+            let contents = objects
+                .iter()
+                .filter(|(key, _)| {
+                    key.strip_prefix(prefix)
+                        .map_or(false, |name| !name.contains('/'))
+                })
+                .map(|(key, object)| {
+                    format!(
+                        "<Contents><Key>{}</Key><LastModified>2023-01-01T00:00:00.000Z</LastModified>\
+                         <ETag>&quot;fake&quot;</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                        key,
+                        object.len()
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                 <Name>{}</Name><Prefix>{}</Prefix><Delimiter>/</Delimiter><KeyCount>{}</KeyCount>\
+                 <MaxKeys>1000</MaxKeys><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                bucket,
+                prefix,
+                contents.len(),
+                contents.concat()
+            )
+        }
+
+        fn query_value(query: &str, name: &str) -> Option<String> {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| decode(value))
+        }
+
+        /// Undoes percent-encoding.
+        fn decode(value: &str) -> String {
+            let bytes = value.as_bytes();
+            let mut decoded = Vec::with_capacity(bytes.len());
+            let mut i = 0;
+            while i < bytes.len() {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|h| std::str::from_utf8(h).ok())
+                    .and_then(|h| u8::from_str_radix(h, 16).ok());
+                match (bytes[i], hex) {
+                    (b'%', Some(byte)) => {
+                        decoded.push(byte);
+                        i += 3;
+                    }
+                    (byte, _) => {
+                        decoded.push(byte);
+                        i += 1;
+                    }
+                }
+            }
+
+            String::from_utf8_lossy(&decoded).to_string()
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use actix_web::web;
+use log::{error, info};
+
+use crate::error::ApiError;
+
+/// The local directory per-request temp upload dirs are created in, from
+/// `TEMP_UPLOAD_DIRECTORY`. Uploads only go there for libraries that can
+/// only open files; they are never put in the storage backend.
+pub struct TempUploadStore {
+    directory: PathBuf,
+}
+
+impl TempUploadStore {
+    /// Creates the directory if it does not exist yet.
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+
+        Ok(Self { directory })
+    }
+
+    pub fn create_dir(&self) -> Result<TempUploadDir, ApiError> {
+        let path = self.directory.join(uuid::Uuid::new_v4().to_string());
+        let lock_path = path.join(".lock");
+
+        if lock_path.exists() {
+            error!("Directory {:?} is locked!", path);
+            return Err(ApiError::ImageUploadError {
+                message: "Failed to upload image".to_string(),
+            });
+        }
+
+        fs::create_dir_all(&path)?;
+        let dir = TempUploadDir { path };
+        fs::write(lock_path, "")?;
+
+        Ok(dir)
+    }
+
+    /// Removes the per-request directories last modified more than `max_age`
+    /// ago, or all of them if `max_age` is `None`. Anything not named like
+    /// one is left alone, in case the directory is shared with other files.
+    /// Returns the number of entries and bytes reclaimed.
+    pub fn sweep(&self, max_age: Option<Duration>) -> io::Result<(usize, u64)> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        let mut reclaimed = 0;
+
+        for entry in fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            if !entry.file_name().to_str().map_or(false, is_upload_dir_name) {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_dir() {
+                continue;
+            }
+
+            if let Some(max_age) = max_age {
+                let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+                if age < max_age {
+                    continue;
+                }
+            }
+
+            let size = disk_usage(&entry.path())?;
+            match fs::remove_dir_all(entry.path()) {
+                Ok(_) => {
+                    removed += 1;
+                    reclaimed += size;
+                }
+                Err(e) => error!("Failed to remove {:?}: {:?}", entry.path(), e),
+            }
+        }
+
+        Ok((removed, reclaimed))
+    }
+}
+
+/// A per-request directory under the temp upload dir that is removed with
+/// everything in it when dropped, including on early returns.
+pub struct TempUploadDir {
+    path: PathBuf,
+}
+
+impl TempUploadDir {
+    /// Writes an upload into the directory and returns its path.
+    pub fn put_upload(&self, name: &str, bytes: &[u8]) -> Result<PathBuf, ApiError> {
+        let path = self.path.join(name);
+        fs::write(&path, bytes)?;
+
+        Ok(path)
+    }
+}
+
+impl Drop for TempUploadDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            error!("Failed to remove {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+pub async fn sweep_periodically(
+    temp_uploads: web::Data<TempUploadStore>,
+    max_age: Duration,
+    interval: Duration,
+) {
+    let mut interval = actix_web::rt::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        let store = temp_uploads.clone();
+        match web::block(move || store.sweep(Some(max_age))).await {
+            Ok(Ok((0, _))) => {}
+            Ok(Ok((removed, reclaimed))) => info!(
+                "Removed {} stale temp upload entries, reclaimed {} bytes",
+                removed, reclaimed
+            ),
+            Ok(Err(e)) => error!("Failed to sweep temp upload dir: {:?}", e),
+            Err(e) => error!("Failed to sweep temp upload dir: {:?}", e),
+        }
+    }
+}
+
+/// Directories are named by [`TempUploadStore::create_dir`] after a UUID.
+fn is_upload_dir_name(name: &str) -> bool {
+    uuid::Uuid::parse_str(name).map_or(false, |id| id.hyphenated().to_string() == name)
+}
+
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += disk_usage(&entry?.path())?;
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_uuids_name_upload_dirs() {
+        assert!(is_upload_dir_name(&uuid::Uuid::new_v4().to_string()));
+        assert!(!is_upload_dir_name(
+            &uuid::Uuid::new_v4().simple().to_string()
+        ));
+        assert!(!is_upload_dir_name("dist"));
+        assert!(!is_upload_dir_name(".env"));
+        assert!(!is_upload_dir_name(""));
+    }
+
+    #[test]
+    fn sweeps_only_upload_dirs_and_removes_them_when_dropped() {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let store = TempUploadStore::new(&directory).unwrap();
+        fs::write(directory.join(".env"), "").unwrap();
+
+        let dir = store.create_dir().unwrap();
+        let path = dir.put_upload("recording.mp4", b"video").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"video");
+        drop(dir);
+        assert!(!path.exists());
+
+        let _leftover = std::mem::ManuallyDrop::new(store.create_dir().unwrap());
+        assert_eq!(store.sweep(Some(Duration::from_secs(60))).unwrap().0, 0);
+        assert_eq!(store.sweep(None).unwrap().0, 1);
+        assert!(directory.join(".env").exists());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::io::Cursor;
+
+use anyhow::Context;
+use image::io::{Limits, Reader};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+use crate::config::DecodeLimits;
+
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1",
+];
+
+/// Screenshot formats accepted by the receipt endpoints.
+///
+/// Every upload is decoded and re-encoded as PNG before it is handed to
+/// `HorseGirlFullDetailImage`, so OpenCV never sees the raw upload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Heic,
+}
+
+impl UploadFormat {
+    /// Detects the format from the leading magic bytes of the file. The
+    /// multipart content type is chosen by the client and is not trusted.
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(UploadFormat::Png)
+        } else if header.starts_with(&[0xff, 0xd8, 0xff]) {
+            Some(UploadFormat::Jpeg)
+        } else if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+            Some(UploadFormat::WebP)
+        } else if header.len() >= 12
+            && &header[4..8] == b"ftyp"
+            && HEIF_BRANDS.contains(&&header[8..12])
+        {
+            Some(UploadFormat::Heic)
+        } else {
+            None
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8], limits: &DecodeLimits) -> anyhow::Result<DynamicImage> {
+        let format = match self {
+            UploadFormat::Png => ImageFormat::Png,
+            UploadFormat::Jpeg => ImageFormat::Jpeg,
+            UploadFormat::WebP => ImageFormat::WebP,
+            UploadFormat::Heic => return decode_heic(bytes, limits),
+        };
+
+        let mut image_limits = Limits::default();
+        image_limits.max_image_width = Some(limits.max_width);
+        image_limits.max_image_height = Some(limits.max_height);
+        image_limits.max_alloc = Some(limits.max_decoded_bytes);
+
+        let mut reader = Reader::with_format(Cursor::new(bytes), format);
+        reader.limits(image_limits);
+
+        Ok(reader.decode()?)
+    }
+}
+
+fn decode_heic(bytes: &[u8], limits: &DecodeLimits) -> anyhow::Result<DynamicImage> {
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(bytes)?;
+    let handle = context.primary_image_handle()?;
+
+    let (width, height) = (handle.width(), handle.height());
+    anyhow::ensure!(
+        width <= limits.max_width && height <= limits.max_height,
+        "HEIC image is too large: {}x{}",
+        width,
+        height
+    );
+    anyhow::ensure!(
+        width as u64 * height as u64 * 4 <= limits.max_decoded_bytes,
+        "HEIC image exceeds the decoded size limit: {}x{}",
+        width,
+        height
+    );
+
+    let image = lib_heif.decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)?;
+
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .context("HEIC image has no interleaved plane")?;
+
+    let row_bytes = plane.width as usize * 4;
+    let mut buffer = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        buffer.extend_from_slice(&row[..row_bytes]);
+    }
+
+    RgbaImage::from_raw(plane.width, plane.height, buffer)
+        .map(DynamicImage::ImageRgba8)
+        .context("HEIC image has an unexpected buffer size")
+}
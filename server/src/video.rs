@@ -0,0 +1,524 @@
+use std::time::Duration;
+
+use image::{DynamicImage, RgbImage};
+use log::{info, warn};
+use opencv::core::Mat;
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::videoio::{self, VideoCapture};
+
+use crate::config::{DecodeLimits, VideoLimits};
+use crate::detail::StitchOptions;
+use crate::error::ApiError;
+use crate::screen::ScrollProfiles;
+use crate::temp_dir::TempUploadStore;
+
+/// Consecutive picked frames keep at least this share of their scroll area
+/// in common, so the stitcher can find the seam between them.
+const MIN_OVERLAP_RATIO: f32 = 0.3;
+
+/// Container formats accepted for screen recordings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    Mp4,
+    WebM,
+}
+
+impl VideoFormat {
+    /// Detects the container from the leading bytes of an upload.
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        const MP4_BRANDS: [&[u8; 4]; 10] = [
+            b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"M4V ",
+            b"qt  ",
+        ];
+
+        if header.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            Some(Self::WebM)
+        } else if header.len() >= 12
+            && &header[4..8] == b"ftyp"
+            && MP4_BRANDS.iter().any(|b| &header[8..12] == *b)
+        {
+            Some(Self::Mp4)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::WebM => "webm",
+        }
+    }
+}
+
+/// A frame picked from a screen recording.
+pub struct Frame {
+    pub image: DynamicImage,
+    pub position: Duration,
+}
+
+/// Samples a screen recording of a screen being scrolled and picks the
+/// fewest frames that still overlap enough to be stitched, in order.
+///
+/// Frames are compared by the scroll area of the screen given by
+/// `options.screen_type`, so its title bar and close button do not count as
+/// scrolled content. Frames that did not scroll since the last picked one
+/// are skipped, and so are frames in which the screen is not found. Picking
+/// more than `max_frames` frames or `max_total_pixels` pixels stops the
+/// extraction early. OpenCV can only open videos from a path, so the
+/// recording is written to a temp upload dir first.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_frames(
+    temp_uploads: &TempUploadStore,
+    video: &[u8],
+    format: VideoFormat,
+    options: &StitchOptions,
+    video_limits: &VideoLimits,
+    decode_limits: &DecodeLimits,
+    max_frames: usize,
+    max_total_pixels: u64,
+) -> Result<Vec<Frame>, ApiError> {
+    let dir = temp_uploads.create_dir()?;
+    let file_path = dir.put_upload(&format!("recording.{}", format.extension()), video)?;
+
+    let file_path = file_path.to_str().ok_or(ApiError::ImageUploadError {
+        message: "Failed to upload video".to_string(),
+    })?;
+
+    let capture = VideoCapture::from_file(file_path, videoio::CAP_ANY).map_err(invalid_video)?;
+    if !capture.is_opened().map_err(invalid_video)? {
+        return Err(invalid_video("Failed to open video"));
+    }
+
+    let picked = pick_frames(
+        SampledFrames {
+            capture,
+            video_limits,
+            decode_limits,
+            next_sample: Duration::ZERO,
+            mat: Mat::default(),
+        },
+        options,
+        max_frames,
+        max_total_pixels,
+    )?;
+    info!(
+        "Picked frames at {:?}",
+        picked.iter().map(|f| f.position).collect::<Vec<_>>()
+    );
+
+    if picked.is_empty() {
+        return Err(invalid_video("No frames found in video"));
+    }
+
+    Ok(picked)
+}
+
+/// Picks frames as described by [`extract_frames`] from the sampled ones.
+fn pick_frames(
+    frames: impl Iterator<Item = Result<Frame, ApiError>>,
+    options: &StitchOptions,
+    max_frames: usize,
+    max_total_pixels: u64,
+) -> Result<Vec<Frame>, ApiError> {
+    let mut picked = PickedFrames {
+        frames: Vec::new(),
+        total_pixels: 0,
+        max_frames,
+        max_total_pixels,
+    };
+    let mut candidate: Option<Frame> = None;
+
+    for frame in frames {
+        let frame = frame?;
+        let Some(last_picked) = picked.frames.last() else {
+            if has_layout(&frame, options) {
+                picked.push(frame)?;
+            }
+            continue;
+        };
+
+        match scroll_offset(last_picked, &frame, options) {
+            Some(0) => {}
+            Some(_) => candidate = Some(frame),
+            None => {
+                // Scrolled too far from the last picked frame: the candidate
+                // is the furthest frame that still overlapped it.
+                if let Some(candidate) = candidate.take() {
+                    picked.push(candidate)?;
+                }
+
+                let last_picked = picked.frames.last().expect("A frame was picked");
+                match scroll_offset(last_picked, &frame, options) {
+                    Some(0) => {}
+                    Some(_) => candidate = Some(frame),
+                    None if has_layout(&frame, options) => {
+                        warn!(
+                            "No overlapping frame before {:?}, the receipt may not stitch",
+                            frame.position
+                        );
+                        picked.push(frame)?;
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    if let Some(frame) = candidate {
+        picked.push(frame)?;
+    }
+
+    Ok(picked.frames)
+}
+
+/// Whether the screen being recorded is found in the frame.
+fn has_layout(frame: &Frame, options: &StitchOptions) -> bool {
+    let found = options.screen_type.find_layout(&frame.image).is_some();
+    if !found {
+        info!(
+            "No {:?} screen found at {:?}",
+            options.screen_type, frame.position
+        );
+    }
+    found
+}
+
+/// How many rows the scroll area of `current` has scrolled up from
+/// `previous`, or `None` if they do not overlap enough to be stitched.
+fn scroll_offset(previous: &Frame, current: &Frame, options: &StitchOptions) -> Option<u32> {
+    ScrollProfiles::new(&[&previous.image, &current.image], options)?
+        .compare(0, 1)
+        .filter(|m| m.overlap as f32 >= (m.offset + m.overlap) as f32 * MIN_OVERLAP_RATIO)
+        .map(|m| m.offset)
+}
+
+/// Frames of a recording, one every `sample_interval`, checked against the
+/// limits as they are decoded.
+struct SampledFrames<'a> {
+    capture: VideoCapture,
+    video_limits: &'a VideoLimits,
+    decode_limits: &'a DecodeLimits,
+    next_sample: Duration,
+    mat: Mat,
+}
+
+impl SampledFrames<'_> {
+    fn next_frame(&mut self) -> Result<Option<Frame>, ApiError> {
+        while self.capture.grab().map_err(invalid_video)? {
+            let position = Duration::from_secs_f64(
+                self.capture
+                    .get(videoio::CAP_PROP_POS_MSEC)
+                    .map_err(invalid_video)?
+                    .max(0.0)
+                    / 1000.0,
+            );
+            if position > self.video_limits.max_duration {
+                return Err(ApiError::VideoTooLong {
+                    limit: self.video_limits.max_duration.as_secs(),
+                });
+            }
+            if position < self.next_sample {
+                continue;
+            }
+            self.next_sample = position + self.video_limits.sample_interval;
+
+            self.capture
+                .retrieve(&mut self.mat, 0)
+                .map_err(invalid_video)?;
+            let image = to_image(&self.mat, self.decode_limits)?;
+
+            return Ok(Some(Frame { image, position }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl Iterator for SampledFrames<'_> {
+    type Item = Result<Frame, ApiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// Frames picked so far, checked against the limits as they are picked so
+/// that a long recording is not held in memory before being rejected.
+struct PickedFrames {
+    frames: Vec<Frame>,
+    total_pixels: u64,
+    max_frames: usize,
+    max_total_pixels: u64,
+}
+
+impl PickedFrames {
+    fn push(&mut self, frame: Frame) -> Result<(), ApiError> {
+        if self.frames.len() >= self.max_frames {
+            return Err(ApiError::TooManyImages {
+                limit: self.max_frames,
+            });
+        }
+
+        self.total_pixels += frame.image.width() as u64 * frame.image.height() as u64;
+        if self.total_pixels > self.max_total_pixels {
+            return Err(ApiError::PixelBudgetExceeded {
+                limit: self.max_total_pixels,
+            });
+        }
+
+        self.frames.push(frame);
+
+        Ok(())
+    }
+}
+
+/// Converts a BGR frame decoded by OpenCV, checking its size first.
+fn to_image(mat: &Mat, decode_limits: &DecodeLimits) -> Result<DynamicImage, ApiError> {
+    let (width, height) = (mat.cols() as u32, mat.rows() as u32);
+    if width > decode_limits.max_width || height > decode_limits.max_height {
+        return Err(ApiError::InvalidParameter {
+            message: "video: frames are too large".to_string(),
+            sensitive_message: Some(format!("{}x{}", width, height)),
+        });
+    }
+
+    let mut rgb = Mat::default();
+    imgproc::cvt_color(mat, &mut rgb, imgproc::COLOR_BGR2RGB, 0).map_err(invalid_video)?;
+    let data = rgb.data_bytes().map_err(invalid_video)?.to_vec();
+
+    RgbImage::from_raw(width, height, data)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| invalid_video("Unexpected frame layout"))
+}
+
+fn invalid_video(e: impl std::fmt::Debug) -> ApiError {
+    ApiError::InvalidParameter {
+        message: "video: invalid or unsupported video".to_string(),
+        sensitive_message: Some(format!("{:?}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{GenericImageView, RgbImage};
+    use opencv::core::{Size, CV_8UC3};
+    use opencv::videoio::VideoWriter;
+
+    use super::*;
+    use crate::screen::tests::{content, options, screenshot};
+    use crate::screen::{self, ScreenType};
+
+    /// A dialog, so the title bar and close button stay put while the
+    /// 370 rows between them scroll.
+    const SCREEN_TYPE: ScreenType = ScreenType::FactorList;
+    const CONTENT_HEIGHT: u32 = 2000;
+    const SCROLL_AREA_HEIGHT: u32 = 370;
+    const MAX_OFFSET: u32 = CONTENT_HEIGHT - SCROLL_AREA_HEIGHT;
+    const SCROLL_STEP: u32 = 40;
+    const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Scroll offsets of a recording that rests at the top, scrolls to the
+    /// bottom and rests there, one frame each.
+    fn scroll() -> Vec<u32> {
+        let mut offsets = vec![0; 5];
+        offsets.extend((SCROLL_STEP..MAX_OFFSET).step_by(SCROLL_STEP as usize));
+        offsets.extend([MAX_OFFSET; 5]);
+        offsets
+    }
+
+    /// Frames of the dialog scrolled to each offset, `FRAME_INTERVAL` apart.
+    fn frames(content: &RgbImage, offsets: &[u32]) -> Vec<Result<Frame, ApiError>> {
+        offsets
+            .iter()
+            .enumerate()
+            .map(|(index, &offset)| {
+                Ok(Frame {
+                    image: screenshot(SCREEN_TYPE, content, offset),
+                    position: FRAME_INTERVAL * index as u32,
+                })
+            })
+            .collect()
+    }
+
+    fn pick(
+        offsets: &[u32],
+        max_frames: usize,
+        max_total_pixels: u64,
+    ) -> Result<Vec<u32>, ApiError> {
+        let frames = pick_frames(
+            frames(&content(CONTENT_HEIGHT), offsets).into_iter(),
+            &options(SCREEN_TYPE, false),
+            max_frames,
+            max_total_pixels,
+        )?;
+
+        Ok(frames
+            .iter()
+            .map(|f| offsets[(f.position.as_millis() / FRAME_INTERVAL.as_millis()) as usize])
+            .collect())
+    }
+
+    /// Height of a stitched dialog: the title bar, the content shown between
+    /// the first and the last frame and the close button.
+    fn stitched_height(first_offset: u32, last_offset: u32) -> u32 {
+        40 + last_offset + SCROLL_AREA_HEIGHT - first_offset + 70
+    }
+
+    #[test]
+    fn picks_the_furthest_overlapping_frames() {
+        // Scroll areas overlap by 30% at least, which is 259 rows of
+        // scrolling at most; the title bar and close button do not count,
+        // and the still frames at either end add nothing.
+        assert_eq!(
+            pick(&scroll(), 30, u64::MAX).unwrap(),
+            [0, 240, 480, 720, 960, 1200, 1440, 1630]
+        );
+    }
+
+    #[test]
+    fn stitches_the_picked_frames_between_the_fixed_rows() {
+        let content = content(CONTENT_HEIGHT);
+        let options = options(SCREEN_TYPE, false);
+        let frames = pick_frames(
+            frames(&content, &scroll()).into_iter(),
+            &options,
+            30,
+            u64::MAX,
+        )
+        .unwrap();
+
+        let images = frames.into_iter().map(|f| f.image).collect::<Vec<_>>();
+        let stitched = screen::stitch(&images, &options).unwrap();
+
+        assert_eq!(stitched.dimensions(), (240, stitched_height(0, MAX_OFFSET)));
+    }
+
+    #[test]
+    fn skips_frames_without_the_screen() {
+        let content = content(CONTENT_HEIGHT);
+        let mut frames = frames(&content, &[0, 200]);
+        frames.insert(
+            0,
+            Ok(Frame {
+                image: DynamicImage::ImageRgb8(content.view(0, 0, 300, 600).to_image()),
+                position: Duration::ZERO,
+            }),
+        );
+
+        let picked = pick_frames(
+            frames.into_iter(),
+            &options(SCREEN_TYPE, false),
+            30,
+            u64::MAX,
+        )
+        .unwrap();
+
+        assert_eq!(picked.len(), 2);
+    }
+
+    #[test]
+    fn picks_one_frame_of_a_still_recording() {
+        assert_eq!(pick(&[0; 10], 30, u64::MAX).unwrap(), [0]);
+    }
+
+    #[test]
+    fn stops_at_the_frame_limit() {
+        let result = pick(&scroll(), 5, u64::MAX);
+
+        assert!(matches!(result, Err(ApiError::TooManyImages { limit: 5 })));
+    }
+
+    #[test]
+    fn stops_at_the_pixel_budget() {
+        let budget = 3 * 300 * 600;
+        let result = pick(&scroll(), 30, budget);
+
+        assert!(matches!(result, Err(ApiError::PixelBudgetExceeded { limit }) if limit == budget));
+    }
+
+    /// Records the dialog scrolled to each offset as an MP4 at 10 fps.
+    fn record(offsets: &[u32]) -> Vec<u8> {
+        let content = content(CONTENT_HEIGHT);
+        let path = std::env::temp_dir().join(format!("{}.mp4", uuid::Uuid::new_v4()));
+        let mut writer = VideoWriter::new(
+            path.to_str().unwrap(),
+            VideoWriter::fourcc('m', 'p', '4', 'v').unwrap(),
+            10.0,
+            Size::new(300, 600),
+            true,
+        )
+        .unwrap();
+        assert!(writer.is_opened().unwrap());
+
+        for &offset in offsets {
+            let mut frame = Mat::new_rows_cols_with_default(600, 300, CV_8UC3, 0.into()).unwrap();
+            let rgb = screenshot(SCREEN_TYPE, &content, offset).to_rgb8();
+            for (bgr, rgb) in frame
+                .data_bytes_mut()
+                .unwrap()
+                .chunks_mut(3)
+                .zip(rgb.pixels())
+            {
+                bgr.copy_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+            }
+            writer.write(&frame).unwrap();
+        }
+        writer.release().unwrap();
+
+        let video = fs::read(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        video
+    }
+
+    fn extract(video: &[u8]) -> Result<Vec<Frame>, ApiError> {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let temp_uploads = TempUploadStore::new(&directory).unwrap();
+
+        let frames = extract_frames(
+            &temp_uploads,
+            video,
+            VideoFormat::Mp4,
+            &options(SCREEN_TYPE, false),
+            &VideoLimits {
+                max_video_bytes: video.len(),
+                max_duration: Duration::from_secs(60),
+                sample_interval: Duration::from_millis(50),
+            },
+            &DecodeLimits {
+                max_width: 8192,
+                max_height: 8192,
+                max_decoded_bytes: 256 * 1024 * 1024,
+            },
+            30,
+            u64::MAX,
+        );
+        fs::remove_dir_all(directory).unwrap();
+        frames
+    }
+
+    #[test]
+    fn sniffs_the_recording() {
+        assert_eq!(VideoFormat::sniff(&record(&[0])), Some(VideoFormat::Mp4));
+        assert_eq!(
+            VideoFormat::sniff(b"\x1A\x45\xDF\xA3webm"),
+            Some(VideoFormat::WebM)
+        );
+        assert_eq!(VideoFormat::sniff(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[test]
+    fn extracts_the_frames_of_a_recording() {
+        let frames = extract(&record(&scroll())).unwrap();
+        assert!(frames.windows(2).all(|f| f[0].position < f[1].position));
+
+        let options = options(SCREEN_TYPE, false);
+        let images = frames.into_iter().map(|f| f.image).collect::<Vec<_>>();
+        let stitched = screen::stitch(&images, &options).unwrap();
+
+        assert_eq!(stitched.dimensions(), (240, stitched_height(0, MAX_OFFSET)));
+    }
+}